@@ -0,0 +1,70 @@
+use std::collections::HashMap;
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use json_search::search::{Database, Entry, Record};
+
+/// The bundled dataset, repeated 10 times with fresh ids.
+fn bundled() -> Database {
+    let mut db = Database::new();
+    for _ in 0..10 {
+        db.load_csv_files(&["assets/data.csv"], |record: Record| Entry::from(record))
+            .unwrap();
+    }
+    db.tokenize_entries().unwrap();
+    db
+}
+
+fn doc_len(c: &mut Criterion) {
+    let db = bundled();
+    let ids = db
+        .entries
+        .iter()
+        .filter_map(|entry| entry["id"].as_u64().map(|id| id as u32))
+        .collect::<Vec<u32>>();
+
+    // the cached length against summing the document's term counts again,
+    // which is what every term lookup used to do. `tf` is private, so this
+    // sums a copy with the same shape: term counts per document id
+    let tf = ids
+        .iter()
+        .map(|id| {
+            let mut counts = HashMap::new();
+            for token in db.tokens_for(*id).unwrap() {
+                *counts.entry(token).or_insert(0u32) += 1;
+            }
+            (*id, counts)
+        })
+        .collect::<HashMap<u32, HashMap<&str, u32>>>();
+    let mut group = c.benchmark_group("document_length");
+    group.bench_function("cached", |b| {
+        b.iter(|| {
+            ids.iter()
+                .map(|id| db.document_length(*id).unwrap())
+                .sum::<usize>()
+        })
+    });
+    group.bench_function("recounted", |b| {
+        b.iter(|| {
+            ids.iter()
+                .map(|id| tf[id].values().sum::<u32>() as usize)
+                .sum::<usize>()
+        })
+    });
+    group.finish();
+
+    let words = [
+        "the", "guide", "life", "book", "king", "love", "world", "war", "man", "cook", "diary",
+        "wimpy", "kid", "harry", "potter", "night",
+    ];
+    let mut group = c.benchmark_group("query_tokens");
+    for n in [1, 4, 16] {
+        let tokens = db.tokenize(&words[..n].join(" "));
+        group.bench_with_input(BenchmarkId::from_parameter(n), &tokens, |b, tokens| {
+            b.iter(|| db.search_tokens(black_box(tokens)).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, doc_len);
+criterion_main!(benches);
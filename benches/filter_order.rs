@@ -0,0 +1,48 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use json_search::{
+    filter::Filter,
+    query::{FilterOrder, Query},
+    search::Database,
+};
+use serde_json::json;
+
+const WORDS: [&str; 8] = [
+    "rust", "guide", "kiwi", "pear", "async", "tokio", "serde", "search",
+];
+
+fn corpus(n: u32) -> Database {
+    let mut db = Database::new();
+    for i in 0..n {
+        let text = (0..6)
+            .map(|j| WORDS[((i * 7 + j * 3) % WORDS.len() as u32) as usize])
+            .collect::<Vec<_>>()
+            .join(" ");
+        db.add(json!({"id": i, "category": i % 20, "text": text}));
+    }
+    db.tokenize_entries().unwrap();
+    db
+}
+
+fn filter_order(c: &mut Criterion) {
+    let db = corpus(10_000);
+    let filters = [
+        ("selective", Filter::eq("category", 3)),
+        ("broad", Filter::lt("category", 18)),
+    ];
+
+    let mut group = c.benchmark_group("filter_order");
+    for (name, filter) in filters {
+        for order in [FilterOrder::BeforeScoring, FilterOrder::AfterScoring] {
+            let query = Query::new("rust guide").filter(filter.clone(), order);
+            group.bench_with_input(
+                BenchmarkId::new(format!("{:?}", order), name),
+                &query,
+                |b, query| b.iter(|| db.search_query(query).unwrap()),
+            );
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, filter_order);
+criterion_main!(benches);
@@ -0,0 +1,44 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use json_search::search::{Database, Entry, Record};
+
+/// The bundled dataset's entries, repeated 10 times with fresh ids.
+fn bundled_entries() -> Vec<serde_json::Value> {
+    let mut db = Database::new();
+    for _ in 0..10 {
+        db.load_csv_files(&["assets/data.csv"], |record: Record| Entry::from(record))
+            .unwrap();
+    }
+    db.entries
+}
+
+fn ingest(c: &mut Criterion) {
+    let entries = bundled_entries();
+    let n = entries.len();
+
+    let mut group = c.benchmark_group("ingest");
+    let load = |mut db: Database, entries: Vec<serde_json::Value>| {
+        for entry in entries {
+            db.add(entry);
+        }
+        db.tokenize_entries().unwrap();
+        db
+    };
+    group.bench_function("new", |b| {
+        b.iter_batched(
+            || entries.clone(),
+            |entries| load(Database::new(), entries),
+            BatchSize::LargeInput,
+        )
+    });
+    group.bench_function("with_capacity", |b| {
+        b.iter_batched(
+            || entries.clone(),
+            |entries| load(Database::with_capacity(n), entries),
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, ingest);
+criterion_main!(benches);
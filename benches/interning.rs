@@ -0,0 +1,133 @@
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    collections::HashMap,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use json_search::search::{Database, Entry, Record};
+
+/// Tracks the bytes currently allocated, to measure what a structure holds.
+struct Counting;
+
+static LIVE: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        LIVE.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        LIVE.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        LIVE.fetch_sub(layout.size(), Ordering::Relaxed);
+        LIVE.fetch_add(new_size, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+fn live() -> usize {
+    LIVE.load(Ordering::Relaxed)
+}
+
+/// The bundled dataset, repeated `copies` times with fresh ids.
+fn bundled(copies: u32) -> Database {
+    let mut db = Database::new();
+    for _ in 0..copies {
+        db.load_csv_files(&["assets/data.csv"], |record: Record| Entry::from(record))
+            .unwrap();
+    }
+    db
+}
+
+/// Per document token lists and term counts keyed by the token strings, the
+/// layout interning replaced.
+type StringIndex = HashMap<u32, (Vec<String>, HashMap<String, u32>)>;
+
+/// The same, with every token stored once and referred to by its position.
+type InternedIndex = (Vec<String>, HashMap<u32, (Vec<u32>, HashMap<u32, u32>)>);
+
+fn string_index(db: &Database, ids: &[u32]) -> StringIndex {
+    ids.iter()
+        .map(|id| {
+            let tokens = db
+                .tokens_for(*id)
+                .unwrap()
+                .into_iter()
+                .map(str::to_string)
+                .collect::<Vec<String>>();
+            let mut tf = HashMap::new();
+            for token in &tokens {
+                *tf.entry(token.clone()).or_insert(0) += 1;
+            }
+            (*id, (tokens, tf))
+        })
+        .collect()
+}
+
+fn interned_index(db: &Database, ids: &[u32]) -> InternedIndex {
+    let mut terms = Vec::new();
+    let mut term_ids = HashMap::new();
+    let mut docs = HashMap::new();
+    for id in ids {
+        let tokens = db
+            .tokens_for(*id)
+            .unwrap()
+            .into_iter()
+            .map(|token| {
+                *term_ids.entry(token).or_insert_with(|| {
+                    terms.push(token.to_string());
+                    terms.len() as u32 - 1
+                })
+            })
+            .collect::<Vec<u32>>();
+        let mut tf = HashMap::new();
+        for term in &tokens {
+            *tf.entry(*term).or_insert(0) += 1;
+        }
+        docs.insert(*id, (tokens, tf));
+    }
+    terms.shrink_to_fit();
+    (terms, docs)
+}
+
+fn measure<T>(build: impl FnOnce() -> T) -> (T, usize) {
+    let before = live();
+    let value = build();
+    (value, live() - before)
+}
+
+fn main() {
+    for copies in [1, 10] {
+        let mut db = bundled(copies);
+        let ((), index) = measure(|| {
+            db.tokenize_entries().unwrap();
+            db.compact();
+        });
+        let ids = db
+            .entries
+            .iter()
+            .filter_map(|entry| entry["id"].as_u64().map(|id| id as u32))
+            .collect::<Vec<u32>>();
+        let (strings, string_bytes) = measure(|| string_index(&db, &ids));
+        let (interned, interned_bytes) = measure(|| interned_index(&db, &ids));
+
+        println!("{} documents:", ids.len());
+        println!(
+            "  tokens and tf keyed by strings: {:>10} bytes",
+            string_bytes
+        );
+        println!(
+            "  tokens and tf interned:         {:>10} bytes",
+            interned_bytes
+        );
+        println!("  whole database index:           {:>10} bytes", index);
+        drop((strings, interned));
+    }
+}
@@ -0,0 +1,214 @@
+use std::fmt::Debug;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::tokenizer::Lexer;
+
+/// Turns text into tokens. The same analyzer is used for indexed values and
+/// for queries, so both sides agree on token shape. Analyzers that wrap an
+/// external tokenizer (e.g. a HuggingFace one) only need to implement
+/// `analyze`.
+pub trait Analyzer: Debug + Send + Sync {
+    fn analyze(&self, text: &str) -> Vec<String>;
+
+    /// Which built-in analyzer this is, so a saved index can record it and
+    /// rebuild it on load. Custom analyzers return `None` and have to be
+    /// passed back in, see `Database::load_index_with`.
+    fn builtin(&self) -> Option<BuiltinAnalyzer> {
+        None
+    }
+}
+
+/// The analyzers this crate provides, as recorded in a saved index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BuiltinAnalyzer {
+    Lexer,
+    Lowercase,
+    Noop,
+    /// Only available with the `jieba` feature.
+    Jieba,
+}
+
+impl BuiltinAnalyzer {
+    /// A new instance of the analyzer. Fails for `Jieba` when the `jieba`
+    /// feature is off.
+    pub fn build(self) -> Result<Box<dyn Analyzer>> {
+        Ok(match self {
+            Self::Lexer => Box::new(LexerAnalyzer),
+            Self::Lowercase => Box::new(LowercaseAnalyzer),
+            Self::Noop => Box::new(NoopAnalyzer),
+            #[cfg(feature = "jieba")]
+            Self::Jieba => Box::new(JiebaAnalyzer::new()),
+            #[cfg(not(feature = "jieba"))]
+            Self::Jieba => anyhow::bail!("The jieba analyzer needs the `jieba` feature"),
+        })
+    }
+}
+
+/// The default: splits with `Lexer`, uppercasing words.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LexerAnalyzer;
+
+impl Analyzer for LexerAnalyzer {
+    fn analyze(&self, text: &str) -> Vec<String> {
+        let chars = text.chars().collect::<Vec<_>>();
+        Lexer::new(&chars).collect()
+    }
+
+    fn builtin(&self) -> Option<BuiltinAnalyzer> {
+        Some(BuiltinAnalyzer::Lexer)
+    }
+}
+
+/// Like `LexerAnalyzer`, but lowercases words instead, for tokens that read
+/// better in debugging output or match what other tools expect. Indexes built
+/// with one casing must be queried with the same one.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LowercaseAnalyzer;
+
+impl Analyzer for LowercaseAnalyzer {
+    fn analyze(&self, text: &str) -> Vec<String> {
+        LexerAnalyzer
+            .analyze(text)
+            .into_iter()
+            .map(|token| token.to_ascii_lowercase())
+            .collect()
+    }
+
+    fn builtin(&self) -> Option<BuiltinAnalyzer> {
+        Some(BuiltinAnalyzer::Lowercase)
+    }
+}
+
+/// Keeps the whole text, untouched, as a single token, so only exact values
+/// match. Empty text produces no token.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoopAnalyzer;
+
+impl Analyzer for NoopAnalyzer {
+    fn analyze(&self, text: &str) -> Vec<String> {
+        if text.is_empty() {
+            return Vec::new();
+        }
+
+        vec![text.to_string()]
+    }
+
+    fn builtin(&self) -> Option<BuiltinAnalyzer> {
+        Some(BuiltinAnalyzer::Noop)
+    }
+}
+
+/// Segments Chinese text into words with jieba, so queries can match words
+/// inside sentences written without spaces. Runs of other scripts are split
+/// with `Lexer` as usual. Building one loads jieba's dictionary, so create it
+/// once and share it.
+#[cfg(feature = "jieba")]
+pub struct JiebaAnalyzer {
+    jieba: jieba_rs::Jieba,
+}
+
+#[cfg(feature = "jieba")]
+impl JiebaAnalyzer {
+    pub fn new() -> Self {
+        Self {
+            jieba: jieba_rs::Jieba::new(),
+        }
+    }
+}
+
+#[cfg(feature = "jieba")]
+impl Default for JiebaAnalyzer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "jieba")]
+impl Debug for JiebaAnalyzer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("JiebaAnalyzer").finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature = "jieba")]
+impl Analyzer for JiebaAnalyzer {
+    fn analyze(&self, text: &str) -> Vec<String> {
+        let is_han = |c: char| matches!(c, '\u{3400}'..='\u{4dbf}' | '\u{4e00}'..='\u{9fff}');
+
+        let mut tokens = Vec::new();
+        for token in self.jieba.cut(text, false) {
+            let word = token.word;
+            if word.chars().any(is_han) {
+                tokens.push(word.chars().filter(|c| c.is_alphanumeric()).collect());
+            } else {
+                tokens.append(&mut LexerAnalyzer.analyze(word));
+            }
+        }
+
+        tokens
+    }
+
+    fn builtin(&self) -> Option<BuiltinAnalyzer> {
+        Some(BuiltinAnalyzer::Jieba)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        query::Query,
+        search::{Database, IndexConfig},
+    };
+
+    #[test]
+    fn analyzers_shape_tokens() {
+        assert_eq!(
+            LexerAnalyzer.analyze("Don't panic, 4.2"),
+            ["DON'T", "PANIC", "4.2"]
+        );
+        assert_eq!(LowercaseAnalyzer.analyze("Don't PANIC"), ["don't", "panic"]);
+        assert_eq!(NoopAnalyzer.analyze(" As Is! "), [" As Is! "]);
+        assert!(NoopAnalyzer.analyze("").is_empty());
+    }
+
+    #[test]
+    fn lowercase_index_matches_queries_in_any_case() {
+        let mut db = Database::with_analyzer(IndexConfig::default(), Box::new(LowercaseAnalyzer));
+        db.add(json!({"id": 1, "text": "Stephen King"}));
+        db.add(json!({"id": 2, "text": "kiwi"}));
+        db.tokenize_entries().unwrap();
+
+        assert_eq!(db.tokens_for(1).unwrap(), ["1", "stephen", "king"]);
+        assert_eq!(db.tokenize("KING"), ["king"]);
+        let results = db.search_query(&Query::new("KING")).unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].0, 1);
+    }
+
+    #[cfg(feature = "jieba")]
+    #[test]
+    fn jieba_segments_chinese_sentences() {
+        let jieba = JiebaAnalyzer::new();
+        assert_eq!(
+            jieba.analyze("我来到北京清华大学"),
+            ["我", "来到", "北京", "清华大学"]
+        );
+        assert_eq!(
+            jieba.analyze("我爱Rust, 2024"),
+            ["我", "爱", "RUST", "2024"]
+        );
+
+        let mut db = Database::with_analyzer(IndexConfig::default(), Box::new(jieba));
+        db.add(json!({"id": 1, "text": "我来到北京清华大学"}));
+        db.add(json!({"id": 2, "text": "上海"}));
+        db.tokenize_entries().unwrap();
+        let results = db.search_query(&Query::new("北京")).unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].0, 1);
+    }
+}
@@ -0,0 +1,106 @@
+use anyhow::{bail, Context, Result};
+
+use crate::search::Database;
+
+/// Relevance metrics averaged over a labeled query set.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Metrics {
+    /// Relevant ids among the top `k`, divided by `k`.
+    pub precision: f32,
+    /// Relevant ids among the top `k`, divided by the number of relevant ids.
+    pub recall: f32,
+    /// Mean reciprocal rank: 1 / the rank of the first relevant id in the top
+    /// `k`, or 0.0 if there is none.
+    pub mrr: f32,
+    /// How many queries were evaluated.
+    pub queries: usize,
+}
+
+/// Runs every query with `Database::search_many` and scores its top `k`
+/// against the ids labeled relevant for it. Each query needs at least one
+/// relevant id, and the first failing query fails the evaluation.
+pub fn evaluate(db: &Database, labeled: &[(&str, &[u32])], k: usize) -> Result<Metrics> {
+    if k == 0 {
+        bail!("k must be at least 1");
+    }
+
+    let queries = labeled.iter().map(|(query, _)| *query).collect::<Vec<_>>();
+    let mut metrics = Metrics {
+        precision: 0.0,
+        recall: 0.0,
+        mrr: 0.0,
+        queries: labeled.len(),
+    };
+    for ((query, relevant), result) in labeled.iter().zip(db.search_many(&queries, k)) {
+        if relevant.is_empty() {
+            bail!("Query `{}` has no relevant ids", query);
+        }
+
+        let result = result.with_context(|| format!("Failed to run query `{}`", query))?;
+        let hits = result
+            .iter()
+            .filter(|(id, _)| relevant.contains(id))
+            .count() as f32;
+        metrics.precision += hits / k as f32;
+        metrics.recall += hits / relevant.len() as f32;
+        metrics.mrr += result
+            .iter()
+            .position(|(id, _)| relevant.contains(id))
+            .map_or(0.0, |rank| 1.0 / (rank + 1) as f32);
+    }
+
+    if metrics.queries > 0 {
+        let n = metrics.queries as f32;
+        metrics.precision /= n;
+        metrics.recall /= n;
+        metrics.mrr /= n;
+    }
+
+    Ok(metrics)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn db() -> Database {
+        let mut db = Database::new();
+        db.add(json!({"id": 1, "text": "apple pie"}));
+        db.add(json!({"id": 2, "text": "apple"}));
+        db.add(json!({"id": 3, "text": "kiwi"}));
+        db.add(json!({"id": 4, "text": "banana"}));
+        db.tokenize_entries().unwrap();
+        db
+    }
+
+    #[test]
+    fn averages_metrics_over_queries() {
+        let metrics = evaluate(&db(), &[("apple", &[2]), ("kiwi", &[4])], 2).unwrap();
+        assert_eq!(
+            metrics,
+            Metrics {
+                precision: 0.25,
+                recall: 0.5,
+                mrr: 0.5,
+                queries: 2,
+            }
+        );
+    }
+
+    #[test]
+    fn reciprocal_rank_uses_the_first_relevant_hit() {
+        let metrics = evaluate(&db(), &[("apple", &[1])], 2).unwrap();
+        assert_eq!(metrics.mrr, 0.5);
+        assert_eq!(metrics.recall, 1.0);
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        let db = db();
+        assert!(evaluate(&db, &[("apple", &[2])], 0).is_err());
+        assert!(evaluate(&db, &[("apple", &[])], 2).is_err());
+        assert_eq!(evaluate(&db, &[], 2).unwrap().queries, 0);
+    }
+}
@@ -0,0 +1,166 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+
+use crate::{query::Query, search::Database};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FederatedResult {
+    /// Index of the database the hit came from, in the order they were passed.
+    pub source: usize,
+    pub id: u32,
+    pub score: f32,
+}
+
+fn merge_top_k(mut results: Vec<FederatedResult>, k: usize) -> Vec<FederatedResult> {
+    results.sort_by(|a, b| {
+        b.score
+            .total_cmp(&a.score)
+            .then_with(|| a.source.cmp(&b.source))
+            .then_with(|| a.id.cmp(&b.id))
+    });
+    results.truncate(k);
+    results
+}
+
+/// Runs `query` against every database, parsed as `Database::search` parses
+/// it, and merges the hits into a global top `k`. Each database scores with its own IDF, so scores from shards with very
+/// different term distributions are not strictly comparable; use
+/// `federate_global_idf` when that matters.
+pub fn federate(dbs: &[&Database], query: &str, k: usize) -> Result<Vec<FederatedResult>> {
+    let mut results = Vec::new();
+    for (source, db) in dbs.iter().enumerate() {
+        for (id, score) in db.search_query(&Query::new(query))? {
+            results.push(FederatedResult { source, id, score });
+        }
+    }
+
+    Ok(merge_top_k(results, k))
+}
+
+/// Like `federate`, but computes IDF over the union of all databases so scores
+/// are comparable across shards, as if they were a single corpus. IDF uses the
+/// first database's formula (`Database::set_idf` and `set_idf_floor`), so the
+/// shards should share those settings.
+pub fn federate_global_idf(
+    dbs: &[&Database],
+    query: &str,
+    k: usize,
+) -> Result<Vec<FederatedResult>> {
+    let Some(first) = dbs.first() else {
+        return Ok(Vec::new());
+    };
+    let n = dbs.iter().map(|db| db.document_count()).sum::<usize>() as f32;
+    let tokens = first.tokenize(query);
+
+    let idf = tokens
+        .iter()
+        .map(|token| {
+            let df = dbs
+                .iter()
+                .map(|db| db.document_frequency(token))
+                .sum::<u32>();
+            let idf = if df == 0 {
+                0.0
+            } else {
+                first.idf_from(n, df as f32)
+            };
+            (token.to_string(), idf)
+        })
+        .collect::<HashMap<String, f32>>();
+
+    let mut results = Vec::new();
+    for (source, db) in dbs.iter().enumerate() {
+        for (id, score) in db.search_tokens_with_idf(&tokens, &idf)? {
+            results.push(FederatedResult { source, id, score });
+        }
+    }
+
+    Ok(merge_top_k(results, k))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::search::{IdfVariant, LogBase};
+
+    fn shard(entries: &[serde_json::Value]) -> Database {
+        let mut db = Database::new();
+        db.set_idf(IdfVariant::Smoothed, LogBase::Two);
+        for entry in entries {
+            db.add(entry.clone());
+        }
+        db.tokenize_entries().unwrap();
+        db
+    }
+
+    #[test]
+    fn global_idf_matches_a_single_corpus() {
+        let a = [
+            json!({"id": 1, "text": "red apple"}),
+            json!({"id": 2, "text": "green pear"}),
+        ];
+        let b = [
+            json!({"id": 3, "text": "red cherry"}),
+            json!({"id": 4, "text": "yellow banana"}),
+            json!({"id": 5, "text": "red kiwi"}),
+        ];
+        let all = a.iter().chain(&b).cloned().collect::<Vec<_>>();
+        let single = shard(&all);
+
+        let hits = federate_global_idf(&[&shard(&a), &shard(&b)], "red apple", 10).unwrap();
+        let expected = single.search_tokens(&single.tokenize("red apple")).unwrap();
+        assert_eq!(hits.len(), expected.len());
+        for hit in hits {
+            let (_, score) = expected.iter().find(|(id, _)| *id == hit.id).unwrap();
+            assert!((hit.score - score).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn federate_parses_queries_like_search() {
+        let a = [
+            json!({"id": 1, "title": "apple", "text": "pear"}),
+            json!({"id": 2, "title": "pear", "text": "apple"}),
+        ];
+        let b = [
+            json!({"id": 3, "title": "apple pie", "text": "kiwi"}),
+            json!({"id": 4, "title": "kiwi", "text": "cherry"}),
+        ];
+        let mut shards = [shard(&a), shard(&b)];
+        for db in &mut shards {
+            db.set_synonyms(HashMap::from([("pear".into(), vec!["cherry".into()])]), 0.5);
+        }
+
+        for query in ["title:apple", "pear", "\"apple pie\" | apple", ""] {
+            let hits = federate(&[&shards[0], &shards[1]], query, 10).unwrap();
+            let mut expected = Vec::new();
+            for (source, db) in shards.iter_mut().enumerate() {
+                for (entry, score) in db.search(query).unwrap() {
+                    let id = entry["id"].as_u64().unwrap() as u32;
+                    expected.push(FederatedResult { source, id, score });
+                }
+            }
+            assert_eq!(hits, merge_top_k(expected, 10), "{}", query);
+        }
+        // the scope keeps 2 out, the synonym brings 4 in
+        let ids = |query| {
+            let mut ids = federate(&[&shards[0], &shards[1]], query, 10)
+                .unwrap()
+                .iter()
+                .map(|hit| hit.id)
+                .collect::<Vec<u32>>();
+            ids.sort_unstable();
+            ids
+        };
+        assert_eq!(ids("title:apple"), [1, 3]);
+        assert_eq!(ids("pear"), [1, 2, 4]);
+    }
+
+    #[test]
+    fn no_databases_no_hits() {
+        assert!(federate_global_idf(&[], "red", 10).unwrap().is_empty());
+    }
+}
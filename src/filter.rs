@@ -0,0 +1,171 @@
+use std::cmp::Ordering;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::search::get_path;
+
+/// A condition on an entry's fields. Fields are top-level keys or dotted paths
+/// into nested objects. Comparisons only match values of the same kind:
+/// numbers with numbers, strings with strings (lexicographically), bools with
+/// bools. A missing field never matches a comparison. Strings compare
+/// case-sensitively unless wrapped in `IgnoreCase`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Filter {
+    Eq {
+        field: String,
+        value: Value,
+    },
+    Lt {
+        field: String,
+        value: Value,
+    },
+    Lte {
+        field: String,
+        value: Value,
+    },
+    Gt {
+        field: String,
+        value: Value,
+    },
+    Gte {
+        field: String,
+        value: Value,
+    },
+    Exists {
+        field: String,
+    },
+    And(Vec<Filter>),
+    Or(Vec<Filter>),
+    Not(Box<Filter>),
+    /// The inner filter, comparing strings without regard to ASCII case, the
+    /// casing the lexer normalizes away.
+    IgnoreCase(Box<Filter>),
+}
+
+impl Filter {
+    pub fn eq(field: &str, value: impl Into<Value>) -> Self {
+        Self::Eq {
+            field: field.to_string(),
+            value: value.into(),
+        }
+    }
+
+    pub fn lt(field: &str, value: impl Into<Value>) -> Self {
+        Self::Lt {
+            field: field.to_string(),
+            value: value.into(),
+        }
+    }
+
+    pub fn lte(field: &str, value: impl Into<Value>) -> Self {
+        Self::Lte {
+            field: field.to_string(),
+            value: value.into(),
+        }
+    }
+
+    pub fn gt(field: &str, value: impl Into<Value>) -> Self {
+        Self::Gt {
+            field: field.to_string(),
+            value: value.into(),
+        }
+    }
+
+    pub fn gte(field: &str, value: impl Into<Value>) -> Self {
+        Self::Gte {
+            field: field.to_string(),
+            value: value.into(),
+        }
+    }
+
+    pub fn exists(field: &str) -> Self {
+        Self::Exists {
+            field: field.to_string(),
+        }
+    }
+
+    /// Wraps the filter so its string comparisons ignore case, e.g.
+    /// `Filter::eq("author", "stephen king").ignore_case()`.
+    pub fn ignore_case(self) -> Self {
+        Self::IgnoreCase(Box::new(self))
+    }
+
+    pub fn matches(&self, entry: &Value) -> bool {
+        self.matches_with(entry, false)
+    }
+
+    fn matches_with(&self, entry: &Value, ignore_case: bool) -> bool {
+        let compare =
+            |field: &str, value: &Value| compare(get_path(entry, field)?, value, ignore_case);
+
+        match self {
+            Self::Eq { field, value } => compare(field, value) == Some(Ordering::Equal),
+            Self::Lt { field, value } => compare(field, value) == Some(Ordering::Less),
+            Self::Lte { field, value } => compare(field, value).is_some_and(Ordering::is_le),
+            Self::Gt { field, value } => compare(field, value) == Some(Ordering::Greater),
+            Self::Gte { field, value } => compare(field, value).is_some_and(Ordering::is_ge),
+            Self::Exists { field } => get_path(entry, field).is_some_and(|v| !v.is_null()),
+            Self::And(filters) => filters.iter().all(|f| f.matches_with(entry, ignore_case)),
+            Self::Or(filters) => filters.iter().any(|f| f.matches_with(entry, ignore_case)),
+            Self::Not(filter) => !filter.matches_with(entry, ignore_case),
+            Self::IgnoreCase(filter) => filter.matches_with(entry, true),
+        }
+    }
+}
+
+fn compare(a: &Value, b: &Value, ignore_case: bool) -> Option<Ordering> {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => a.as_f64()?.partial_cmp(&b.as_f64()?),
+        (Value::String(a), Value::String(b)) if ignore_case => {
+            Some(a.to_ascii_uppercase().cmp(&b.to_ascii_uppercase()))
+        }
+        (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+        (Value::Bool(a), Value::Bool(b)) => Some(a.cmp(b)),
+        _ if a == b => Some(Ordering::Equal),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn compares_values_of_the_same_kind() {
+        let entry = json!({"price": 8, "title": "Dune", "new": true, "author": {"name": "Frank"}});
+        assert!(Filter::eq("price", 8.0).matches(&entry));
+        assert!(Filter::lt("price", 10).matches(&entry));
+        assert!(Filter::gte("price", 8).matches(&entry));
+        assert!(!Filter::gt("price", "7").matches(&entry));
+        assert!(Filter::lt("title", "Emma").matches(&entry));
+        assert!(Filter::eq("new", true).matches(&entry));
+        assert!(Filter::eq("author.name", "Frank").matches(&entry));
+        assert!(!Filter::lte("missing", 1).matches(&entry));
+    }
+
+    #[test]
+    fn combines_filters() {
+        let entry = json!({"price": 8, "genre": null});
+        let cheap = Filter::lt("price", 10);
+        let pricey = Filter::gt("price", 100);
+        assert!(Filter::And(vec![cheap.clone(), Filter::exists("price")]).matches(&entry));
+        assert!(Filter::Or(vec![pricey.clone(), cheap]).matches(&entry));
+        assert!(Filter::Not(Box::new(pricey)).matches(&entry));
+        assert!(!Filter::exists("genre").matches(&entry));
+    }
+
+    #[test]
+    fn ignore_case_only_affects_strings() {
+        let entry = json!({"author": "Stephen King", "year": 2020});
+        let author = Filter::eq("author", "stephen king");
+        assert!(!author.matches(&entry));
+        assert!(author.ignore_case().matches(&entry));
+        assert!(Filter::lt("author", "t").ignore_case().matches(&entry));
+        assert!(Filter::eq("year", 2020).ignore_case().matches(&entry));
+        let nested = Filter::Not(Box::new(Filter::eq("author", "STEPHEN KING")));
+        assert!(!nested.ignore_case().matches(&entry));
+    }
+}
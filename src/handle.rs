@@ -0,0 +1,87 @@
+use std::sync::Arc;
+
+use arc_swap::ArcSwap;
+
+use crate::search::Database;
+
+/// A shared, swappable database for servers that reindex in the background:
+/// build a new `Database` off to the side, then `store` it. Readers `load` an
+/// `Arc` and search it for as long as they like, so a swap never disturbs a
+/// search in flight and nobody waits on indexing. Loads and stores are
+/// lock-free atomic pointer swaps. Searching through `&Database` bypasses the
+/// result cache, see `Database::search_until` and `Database::search_query`.
+#[derive(Debug)]
+pub struct SearchHandle {
+    current: ArcSwap<Database>,
+}
+
+impl SearchHandle {
+    pub fn new(db: Database) -> Self {
+        Self {
+            current: ArcSwap::from_pointee(db),
+        }
+    }
+
+    /// The current database. Later swaps don't affect the returned one.
+    pub fn load(&self) -> Arc<Database> {
+        self.current.load_full()
+    }
+
+    /// Makes `db` the current database and returns the previous one, which is
+    /// freed once its last reader drops it.
+    pub fn store(&self, db: Database) -> Arc<Database> {
+        self.current.swap(Arc::new(db))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::query::Query;
+
+    fn db_with(word: &str) -> Database {
+        let mut db = Database::new();
+        db.add(json!({"id": 1, "text": word}));
+        db.add(json!({"id": 2, "text": "kiwi"}));
+        db.tokenize_entries().unwrap();
+        db
+    }
+
+    fn hits(db: &Database, word: &str) -> usize {
+        db.search_query(&Query::new(word)).unwrap().len()
+    }
+
+    #[test]
+    fn readers_keep_their_database_across_swaps() {
+        let handle = SearchHandle::new(db_with("apple"));
+        let held = handle.load();
+
+        let previous = handle.store(db_with("banana"));
+        assert!(Arc::ptr_eq(&held, &previous));
+        assert_eq!(hits(&held, "apple"), 1);
+        assert_eq!(hits(&handle.load(), "apple"), 0);
+        assert_eq!(hits(&handle.load(), "banana"), 1);
+    }
+
+    #[test]
+    fn concurrent_readers_see_a_whole_database() {
+        let handle = SearchHandle::new(db_with("apple"));
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..200 {
+                        let db = handle.load();
+                        assert_eq!(hits(&db, "apple") + hits(&db, "banana"), 1);
+                    }
+                });
+            }
+            for i in 0..50 {
+                handle.store(db_with(if i % 2 == 0 { "banana" } else { "apple" }));
+            }
+        });
+    }
+}
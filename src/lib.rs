@@ -0,0 +1,9 @@
+pub mod analyzer;
+pub mod evaluate;
+pub mod federate;
+pub mod filter;
+pub mod handle;
+pub mod query;
+pub mod search;
+pub mod store;
+pub mod tokenizer;
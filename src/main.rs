@@ -6,13 +6,12 @@ use std::{
 use anyhow::{Context, Result};
 use axum::{
     extract::{Query, State},
+    http::StatusCode,
     routing, Json, Router,
 };
+use json_search::search;
 use serde::{Deserialize, Serialize};
 
-mod search;
-mod tokenizer;
-
 #[derive(Debug)]
 struct AppState {
     db: search::Database,
@@ -22,18 +21,15 @@ struct AppState {
 async fn main() -> Result<()> {
     let mut db = search::Database::new();
 
-    // read file from assets
-    csv::Reader::from_path("assets/data.csv")
-        .context("Failed to read file")?
-        .deserialize()
-        .for_each(|record| {
-            let mapped_record: search::Record = record.unwrap();
-            let mut entry = search::Entry::from(mapped_record);
-            entry.id = db.entries.len() as u32 + 1;
-            let json_val = serde_json::to_value(entry).unwrap();
-
-            db.add(json_val);
-        });
+    // read files from assets
+    let reports = db.load_csv_files(&["assets/data.csv"], |record: search::Record| {
+        search::Entry::from(record)
+    })?;
+    for report in reports {
+        for error in &report.errors {
+            eprintln!("{}: {}", report.path.display(), error);
+        }
+    }
 
     db.tokenize_entries()
         .context("Failed to tokenize entries")?;
@@ -70,24 +66,31 @@ struct SearchQuery {
 async fn handle_search(
     qs: Query<SearchQuery>,
     State(app_state): State<Arc<Mutex<AppState>>>,
-) -> Json<SearchResponse> {
+) -> Result<Json<SearchResponse>, (StatusCode, String)> {
     let now = std::time::Instant::now();
 
     let query_term = qs.query.clone().unwrap_or_default();
 
-    let search_result = app_state
-        .lock()
-        .unwrap()
-        .db
-        .search(query_term.as_str())
-        .unwrap();
+    // release the lock before looking at the result, so a bad query can't
+    // poison it
+    let search_result = {
+        let mut app_state = app_state.lock().unwrap();
+        app_state.db.search(query_term.as_str()).and_then(|result| {
+            let candidates = app_state.db.candidate_count(query_term.as_str())?;
+            Ok((result, candidates))
+        })
+    };
+    let (search_result, candidates) =
+        search_result.map_err(|e| (StatusCode::BAD_REQUEST, format!("{:#}", e)))?;
 
     let response = SearchResponse {
         hits: search_result.iter().take(10).map(|r| r.0.clone()).collect(),
-        total_hits: search_result.len() as u32,
+        // results stop at `max_results`, candidates don't; an empty query has
+        // no candidates but can still list documents
+        total_hits: candidates.max(search_result.len()) as u32,
         query: query_term,
         took: now.elapsed().as_millis(),
     };
 
-    Json(response)
+    Ok(Json(response))
 }
@@ -0,0 +1,206 @@
+use std::collections::HashMap;
+
+use crate::filter::Filter;
+
+/// One whitespace-separated piece of a query string, optionally scoped to a
+/// field.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Clause {
+    pub field: Option<String>,
+    /// The clause's text, or each of its `|`-separated alternatives.
+    pub alternatives: Vec<String>,
+    /// Whether any of the clause was in double quotes.
+    pub quoted: bool,
+}
+
+/// A query string with per-query scoring options, for `Database::search_query`.
+/// Options travel with the query rather than living in the database, so
+/// searches sharing a database can score differently at the same time.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Query {
+    pub text: String,
+    /// Multiplies what each field contributes to the score, keyed by field
+    /// path. A boost covers the fields nested below it, the most specific one
+    /// applies, and unboosted fields count 1.0.
+    pub field_boosts: HashMap<String, f32>,
+    /// Only entries matching this are returned.
+    pub filter: Option<Filter>,
+    pub filter_order: FilterOrder,
+}
+
+/// When `Query::filter` is checked. Both give the same results, and either
+/// way only entries containing a query term are fetched to check the filter;
+/// they differ in which documents get scored. `benches/filter_order.rs`
+/// compares the two.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FilterOrder {
+    /// Check the filter on the documents containing query terms, going by
+    /// the index, and only score those that pass, which saves scoring work
+    /// when the filter rejects most of them.
+    #[default]
+    BeforeScoring,
+    /// Score first and only check the filter on documents with a score
+    /// above zero, which saves filter checks when scoring drops many of the
+    /// documents containing query terms, e.g. tokens found everywhere.
+    AfterScoring,
+}
+
+impl Query {
+    pub fn new(text: &str) -> Self {
+        Self {
+            text: text.to_string(),
+            field_boosts: HashMap::new(),
+            filter: None,
+            filter_order: FilterOrder::default(),
+        }
+    }
+
+    pub fn filter(mut self, filter: Filter, order: FilterOrder) -> Self {
+        self.filter = Some(filter);
+        self.filter_order = order;
+        self
+    }
+
+    pub fn boost(mut self, field: &str, factor: f32) -> Self {
+        self.field_boosts.insert(field.to_string(), factor);
+        self
+    }
+}
+
+/// Splits a query string into clauses.
+///
+/// - Clauses are separated by whitespace outside of quotes.
+/// - `field:text` scopes `text` to `field`, a top-level key or dotted path such
+///   as `author.name`. Only the first unquoted, unescaped colon counts, and the
+///   field part must not be empty, so `:king` is an unscoped clause.
+/// - `|` separates alternatives within a clause: `color:red|crimson` matches
+///   either word in `color`. It binds tighter than whitespace, so `red|crimson
+///   dress` is "red or crimson", then "dress". Alternatives are scored like
+///   synonyms, summed; under `Operator::And` a document needs just one of them.
+///   There is no NOT.
+/// - Double quotes group text, spaces, colons and bars included, into one
+///   clause: `author:"stephen king"` or `"ratio 1:2"`. A quoted clause without
+///   alternatives is an exact phrase when searching, see `Database::search`.
+/// - A backslash makes the next character literal, e.g. `12\:30` or `\|`.
+pub fn parse(query: &str) -> Vec<Clause> {
+    let mut clauses = Vec::new();
+    let mut field = None;
+    let mut alternatives = Vec::new();
+    let mut text = String::new();
+    let mut quoted = false;
+    let mut was_quoted = false;
+    let mut has_content = false;
+
+    let mut push_clause = |field: &mut Option<String>,
+                           alternatives: &mut Vec<String>,
+                           text: &mut String,
+                           was_quoted: &mut bool| {
+        alternatives.push(std::mem::take(text));
+        alternatives.retain(|a| !a.is_empty());
+        clauses.push(Clause {
+            field: field.take(),
+            alternatives: std::mem::take(alternatives),
+            quoted: std::mem::take(was_quoted),
+        });
+    };
+
+    let mut chars = query.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                if let Some(escaped) = chars.next() {
+                    text.push(escaped);
+                    has_content = true;
+                }
+            }
+            '"' => {
+                quoted = !quoted;
+                was_quoted = true;
+                has_content = true;
+            }
+            ':' if !quoted && field.is_none() && alternatives.is_empty() && !text.is_empty() => {
+                field = Some(std::mem::take(&mut text));
+            }
+            '|' if !quoted => {
+                alternatives.push(std::mem::take(&mut text));
+            }
+            c if c.is_whitespace() && !quoted => {
+                if has_content || field.is_some() {
+                    push_clause(&mut field, &mut alternatives, &mut text, &mut was_quoted);
+                }
+                alternatives.clear();
+                has_content = false;
+            }
+            c => {
+                text.push(c);
+                has_content = true;
+            }
+        }
+    }
+    if has_content || field.is_some() {
+        push_clause(&mut field, &mut alternatives, &mut text, &mut was_quoted);
+    }
+
+    clauses
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn clause(field: Option<&str>, alternatives: &[&str], quoted: bool) -> Clause {
+        Clause {
+            field: field.map(str::to_string),
+            alternatives: alternatives.iter().map(|a| a.to_string()).collect(),
+            quoted,
+        }
+    }
+
+    #[test]
+    fn splits_scoped_and_unscoped_clauses() {
+        assert_eq!(
+            parse("author:king  horror"),
+            [
+                clause(Some("author"), &["king"], false),
+                clause(None, &["horror"], false),
+            ]
+        );
+        assert_eq!(
+            parse("author.name:king"),
+            [clause(Some("author.name"), &["king"], false)]
+        );
+        assert_eq!(parse(":king"), [clause(None, &[":king"], false)]);
+        assert_eq!(parse("a:b:c"), [clause(Some("a"), &["b:c"], false)]);
+        assert!(parse("   ").is_empty());
+    }
+
+    #[test]
+    fn quotes_and_escapes_keep_text_together() {
+        assert_eq!(
+            parse(r#"author:"stephen king" "ratio 1:2""#),
+            [
+                clause(Some("author"), &["stephen king"], true),
+                clause(None, &["ratio 1:2"], true),
+            ]
+        );
+        assert_eq!(
+            parse(r"12\:30 a\|b"),
+            [
+                clause(None, &["12:30"], false),
+                clause(None, &["a|b"], false),
+            ]
+        );
+    }
+
+    #[test]
+    fn bars_separate_alternatives() {
+        assert_eq!(
+            parse("color:red|crimson dress"),
+            [
+                clause(Some("color"), &["red", "crimson"], false),
+                clause(None, &["dress"], false),
+            ]
+        );
+        assert_eq!(parse("red||"), [clause(None, &["red"], false)]);
+    }
+}
@@ -1,9 +1,43 @@
-use std::collections::HashMap;
+use std::{
+    borrow::Cow,
+    cmp::Ordering,
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
+    hash::{Hash, Hasher},
+    io::{BufRead, BufReader, Read, Write},
+    ops::Range,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, Instant},
+};
 
-use anyhow::{Context, Result};
-use serde::{Deserialize, Serialize};
+use anyhow::{bail, Context, Result};
+use serde::{de::DeserializeOwned, Deserialize, Serialize, Serializer};
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
 
-use crate::tokenizer;
+use crate::{
+    analyzer::{Analyzer, BuiltinAnalyzer, LexerAnalyzer},
+    filter::Filter,
+    query::{self, FilterOrder, Query},
+    store::DocumentStore,
+};
+
+const DEADLINE_CHECK_INTERVAL: usize = 256;
+/// Prefix of the tokens emitted for object keys when
+/// `IndexConfig::index_field_keys` is on. The lexer never produces a `:`, so
+/// these can't collide with tokens taken from values.
+pub const FIELD_KEY_PREFIX: &str = "FIELD:";
+const DEFAULT_MAX_QUERY_LENGTH: usize = 4096;
+const DEFAULT_MAX_QUERY_TOKENS: usize = 256;
+const DEFAULT_MAX_RESULTS: usize = 1000;
+const DEFAULT_MAX_DEPTH: usize = 128;
+const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(60);
+/// Written at the start of every saved index, followed by the format version.
+const INDEX_MAGIC: &[u8; 4] = b"JSIX";
+/// Bumped whenever the serialized layout of `Database` changes incompatibly,
+/// including a field added to or removed from the snapshot, in the same
+/// change. `snapshot_layout_is_pinned_to_the_format_version` lists the fields
+/// each version has.
+const INDEX_FORMAT_VERSION: u32 = 5;
 
 #[derive(Debug, Deserialize)]
 pub struct Record {
@@ -13,19 +47,48 @@ pub struct Record {
     #[serde(rename = "Author")]
     author: String,
 
-    #[serde(rename = "User Rating")]
+    #[serde(rename = "User Rating", deserialize_with = "lenient_number")]
     user_rating: f64,
 
-    #[serde(rename = "Reviews")]
+    #[serde(rename = "Reviews", deserialize_with = "lenient_number")]
     reviews: u32,
 
-    #[serde(rename = "Price")]
+    #[serde(rename = "Price", deserialize_with = "lenient_number")]
     price: f64,
 
-    #[serde(rename = "Year")]
+    #[serde(rename = "Year", deserialize_with = "lenient_number")]
     year: u32,
 }
 
+/// Parses a CSV number written by hand: surrounding whitespace and thousands
+/// separators (`1,200` or `1 200`) are ignored. Blank cells and placeholders
+/// like `N/A` are errors, so the row is reported and skipped instead of
+/// getting a made-up value.
+fn lenient_number<'de, D, T>(deserializer: D) -> std::result::Result<T, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    let raw = String::deserialize(deserializer)?;
+    let trimmed = raw.trim();
+    if trimmed.is_empty()
+        || ["N/A", "NA", "-"]
+            .iter()
+            .any(|m| trimmed.eq_ignore_ascii_case(m))
+    {
+        return Err(serde::de::Error::custom(format!("missing value {:?}", raw)));
+    }
+
+    let digits = trimmed
+        .chars()
+        .filter(|c| *c != ',' && !c.is_whitespace())
+        .collect::<String>();
+    digits
+        .parse()
+        .map_err(|err| serde::de::Error::custom(format!("invalid number {:?}: {}", raw, err)))
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Entry {
     pub id: u32,
@@ -51,182 +114,6032 @@ impl From<Record> for Entry {
     }
 }
 
-#[derive(Debug)]
-struct CachedResult {
-    result: Vec<(serde_json::Value, f32)>,
-    expiration: std::time::Instant,
+/// What `load_csv_files` read from a single file.
+#[derive(Debug, Clone)]
+pub struct LoadReport {
+    pub path: PathBuf,
+    /// Rows that were added to the database.
+    pub rows: usize,
+    /// One message per row that failed to parse or serialize; those rows are
+    /// skipped.
+    pub errors: Vec<String>,
 }
 
-#[derive(Debug)]
-pub struct Database {
-    pub entries: Vec<serde_json::Value>,
-    tokens: HashMap<u32, Vec<String>>,
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SortOrder {
+    Asc,
+    Desc,
+}
 
-    tf: HashMap<u32, HashMap<String, u32>>,
-    // tokenizer: Tokenizer,
-    cached_results: HashMap<String, CachedResult>,
+/// A secondary sort applied to results with equal scores. Entries missing the
+/// field sort after those that have it, regardless of order.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SortKey {
+    pub field: String,
+    pub order: SortOrder,
 }
 
-impl Database {
-    pub fn new() -> Self {
+impl SortKey {
+    pub fn asc(field: &str) -> Self {
         Self {
-            entries: Vec::new(),
-            tokens: HashMap::new(),
-            tf: HashMap::new(),
-            cached_results: HashMap::new(),
+            field: field.to_string(),
+            order: SortOrder::Asc,
         }
     }
 
-    pub fn add(&mut self, entry: serde_json::Value) {
-        self.entries.push(entry);
+    pub fn desc(field: &str) -> Self {
+        Self {
+            field: field.to_string(),
+            order: SortOrder::Desc,
+        }
+    }
+
+    fn compare(&self, a: &serde_json::Value, b: &serde_json::Value) -> Ordering {
+        let (a, b) = (a.get(&self.field), b.get(&self.field));
+        let ord = compare_values(a, b);
+        match (self.order, a, b) {
+            (SortOrder::Desc, Some(_), Some(_)) => ord.reverse(),
+            _ => ord,
+        }
+    }
+}
+
+/// What searching for an empty or all-whitespace query returns.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub enum EmptyQuery {
+    /// Nothing.
+    #[default]
+    None,
+    /// Every document, ranked as if they all scored 1.0, so tie breakers and
+    /// demotions still order them.
+    All,
+    /// Every document, ordered by the key and then by ascending id. Scores are
+    /// all 1.0.
+    AllSortedBy(SortKey),
+}
+
+/// Which of several entries with equal scores rank first, and so which of
+/// them survive the `set_max_results` cut.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum TiePolicy {
+    /// Order by the keys passed to `set_tie_breakers`, then by ascending id.
+    #[default]
+    TieBreakers,
+    /// Ignore the tie breakers and keep the entries with the lowest ids, i.e.
+    /// the earliest added.
+    LowestId,
+}
+
+fn compare_values(a: Option<&serde_json::Value>, b: Option<&serde_json::Value>) -> Ordering {
+    match (a, b) {
+        (Some(serde_json::Value::Number(a)), Some(serde_json::Value::Number(b))) => a
+            .as_f64()
+            .unwrap_or(f64::NAN)
+            .total_cmp(&b.as_f64().unwrap_or(f64::NAN)),
+        (Some(serde_json::Value::String(a)), Some(serde_json::Value::String(b))) => a.cmp(b),
+        (Some(serde_json::Value::Bool(a)), Some(serde_json::Value::Bool(b))) => a.cmp(b),
+        (Some(a), Some(b)) => a.to_string().cmp(&b.to_string()),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => Ordering::Equal,
     }
+}
+
+/// The boost of the most specific field in `boosts` covering `path`.
+fn boost_for(boosts: &HashMap<String, f32>, path: &str) -> f32 {
+    boosts
+        .iter()
+        .filter(|(field, _)| path_covers(field, path))
+        .max_by_key(|(field, _)| field.len())
+        .map_or(1.0, |(_, boost)| *boost)
+}
 
-    fn get_tokens(&self, val: &serde_json::Value) -> Option<Vec<String>> {
-        return match val {
+/// Hashes `entry` walking it without recursion, so an entry nested too deep
+/// to index gets as far as the depth check instead of overflowing the stack.
+fn content_hash(entry: &serde_json::Value) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    let mut stack = vec![entry];
+    while let Some(value) = stack.pop() {
+        std::mem::discriminant(value).hash(&mut hasher);
+        match value {
+            serde_json::Value::Null => {}
+            serde_json::Value::Bool(b) => b.hash(&mut hasher),
+            serde_json::Value::Number(n) => n.to_string().hash(&mut hasher),
+            serde_json::Value::String(s) => s.hash(&mut hasher),
+            serde_json::Value::Array(arr) => {
+                arr.len().hash(&mut hasher);
+                stack.extend(arr.iter().rev());
+            }
             serde_json::Value::Object(map) => {
-                let mut tokens = Vec::new();
-                for (_, value) in map {
-                    match &mut self.get_tokens(value) {
-                        Some(v) => tokens.append(v),
-                        None => {}
-                    }
+                map.len().hash(&mut hasher);
+                for (key, value) in map.iter().rev() {
+                    key.hash(&mut hasher);
+                    stack.push(value);
                 }
-                Some(tokens)
             }
+        }
+    }
+    hasher.finish()
+}
+
+fn fold_diacritics(text: &str) -> String {
+    text.nfd().filter(|c| !is_combining_mark(*c)).collect()
+}
+
+/// `token` without trailing fractional zeros, or `None` if it is not a decimal
+/// number or is already canonical.
+fn canonical_number(token: &str) -> Option<String> {
+    let (whole, fraction) = token.split_once('.')?;
+    let is_digits = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_digit());
+    if !is_digits(whole) || !is_digits(fraction) || !fraction.ends_with('0') {
+        return None;
+    }
+
+    let fraction = fraction.trim_end_matches('0');
+    if fraction.is_empty() {
+        return Some(whole.to_string());
+    }
+
+    Some(format!("{}.{}", whole, fraction))
+}
+
+/// Unscoped, unweighted terms for already analyzed tokens.
+fn token_terms(tokens: &[String]) -> Vec<QueryTerm> {
+    tokens
+        .iter()
+        .enumerate()
+        .map(|(group, t)| QueryTerm {
+            token: t.to_string(),
+            weight: 1.0,
+            field: None,
+            group,
+            phrase: None,
+        })
+        .collect()
+}
+
+fn field_key_token(path: &str) -> String {
+    format!("{}{}", FIELD_KEY_PREFIX, path.to_uppercase())
+}
+
+/// How many objects and arrays deep `value` nests, walking it without
+/// recursion. Stops early once `limit` is exceeded.
+fn nesting_depth(value: &serde_json::Value, limit: usize) -> usize {
+    let mut deepest = 0;
+    let mut stack = vec![(value, 0)];
+    while let Some((value, depth)) = stack.pop() {
+        let children: Box<dyn Iterator<Item = &serde_json::Value>> = match value {
+            serde_json::Value::Object(map) => Box::new(map.values()),
+            serde_json::Value::Array(arr) => Box::new(arr.iter()),
+            _ => continue,
+        };
+        deepest = deepest.max(depth + 1);
+        if deepest > limit {
+            break;
+        }
+        stack.extend(children.map(|child| (child, depth + 1)));
+    }
+
+    deepest
+}
+
+/// Approximate heap bytes held by `value`, walking it without recursion.
+/// Object entries are counted as a key and a value, ignoring map overhead.
+fn json_heap_bytes(value: &serde_json::Value) -> usize {
+    let entry = std::mem::size_of::<String>() + std::mem::size_of::<serde_json::Value>();
+
+    let mut bytes = 0;
+    let mut stack = vec![value];
+    while let Some(value) = stack.pop() {
+        match value {
+            serde_json::Value::String(s) => bytes += s.capacity(),
             serde_json::Value::Array(arr) => {
-                let mut tokens = Vec::new();
-                for value in arr {
-                    match &mut self.get_tokens(value) {
-                        Some(v) => tokens.append(v),
-                        None => {}
-                    }
+                bytes += arr.capacity() * std::mem::size_of::<serde_json::Value>();
+                stack.extend(arr);
+            }
+            serde_json::Value::Object(map) => {
+                for (key, value) in map {
+                    bytes += entry + key.capacity();
+                    stack.push(value);
                 }
-                Some(tokens)
             }
-            serde_json::Value::String(s) => Some(vec![s.to_string()]),
-            serde_json::Value::Number(n) => Some(vec![n.to_string()]),
-            serde_json::Value::Bool(b) => Some(vec![b.to_string()]),
-            serde_json::Value::Null => None,
-        };
+            _ => {}
+        }
     }
 
-    pub fn tokenize_entries(&mut self) -> Result<()> {
-        for entry in &self.entries {
-            let entry_tokens = self
-                .get_tokens(entry)
-                .context("Failed to get tokens")?
-                .iter()
-                .map(|x| {
-                    tokenizer::Lexer::new(x.to_owned().chars().collect::<Vec<_>>().as_slice())
-                        .into_iter()
-                        .map(|y| y.to_ascii_uppercase())
-                        .collect::<Vec<String>>()
-                })
-                .flatten()
-                .collect::<Vec<String>>();
+    bytes
+}
 
-            /*
-                        let tokens = tokenizer::Lexer::new(query.chars().collect::<Vec<_>>().as_slice())
-                            .into_iter()
-                            .map(|x| x.to_ascii_uppercase())
-                            .collect::<Vec<String>>();
-            */
+/// Bytes allocated for a map's buckets, its keys' and values' own heap data
+/// aside: one `(K, V)` slot plus a control byte per bucket.
+fn map_bytes<K, V>(map: &HashMap<K, V>) -> usize {
+    map.capacity() * (std::mem::size_of::<(K, V)>() + 1)
+}
 
-            let entry_id_key = entry.get("id").context("Failed to get id")?;
-            let id = entry_id_key
-                .as_u64()
-                .context("Failed to convert id to u64")?;
+/// Bytes allocated for a vector's elements, given the vector's capacity.
+fn vec_bytes<T>(_: &[T], capacity: usize) -> usize {
+    capacity * std::mem::size_of::<T>()
+}
 
-            self.tokens.insert(id as u32, entry_tokens.clone());
+pub(crate) fn get_path<'a>(
+    entry: &'a serde_json::Value,
+    path: &str,
+) -> Option<&'a serde_json::Value> {
+    path.split('.')
+        .try_fold(entry, |value, key| value.as_object()?.get(key))
+}
 
-            let mut tf = HashMap::new();
-            for token in entry_tokens {
-                let count = tf.entry(token.to_string()).or_insert(0);
-                *count += 1;
-            }
-            self.tf.insert(id as u32, tf);
+fn path_covers(field: &str, path: &str) -> bool {
+    path == field || (path.starts_with(field) && path[field.len()..].starts_with('.'))
+}
+
+/// Controls which parts of a document are tokenized. Fields are top-level keys
+/// or dotted paths into nested objects (e.g. `"author.name"`); array elements
+/// share their parent's path. A field covers everything nested below it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndexConfig {
+    include_fields: Vec<String>,
+    exclude_fields: Vec<String>,
+    id_field: String,
+    recency_field: Option<String>,
+    fold_diacritics: bool,
+    folded_variants: bool,
+    normalize_numbers: bool,
+    positional_arrays: bool,
+    index_field_keys: bool,
+    max_depth: usize,
+}
+
+impl Default for IndexConfig {
+    fn default() -> Self {
+        Self {
+            include_fields: Vec::new(),
+            exclude_fields: Vec::new(),
+            id_field: "id".to_string(),
+            recency_field: None,
+            fold_diacritics: false,
+            folded_variants: false,
+            normalize_numbers: false,
+            positional_arrays: false,
+            index_field_keys: false,
+            max_depth: DEFAULT_MAX_DEPTH,
         }
+    }
+}
 
-        Ok(())
+impl IndexConfig {
+    /// Sets the numeric field (e.g. a year or a timestamp) used by
+    /// `Database::search_recent`.
+    pub fn recency_field(mut self, field: &str) -> Self {
+        self.recency_field = Some(field.to_string());
+        self
     }
 
-    fn get_tf(&self, key: &u32, token: &str) -> f32 {
-        let top = self.tf.get(key).unwrap().get(token).clone().unwrap_or(&0);
-        let bottom = self.tf.get(key).unwrap().values().sum::<u32>();
+    /// Strips diacritics from indexed and query tokens so "cafe" matches
+    /// "Café". Stored entries keep their original text.
+    pub fn fold_diacritics(mut self, fold: bool) -> Self {
+        self.fold_diacritics = fold;
+        self
+    }
 
-        return *top as f32 / bottom as f32;
+    /// Indexes every token both as-is and with its diacritics stripped, so
+    /// queries for "café" and "cafe" both match "Café" while queries are left
+    /// unfolded. The folded variant is only added where
+    /// it differs from the original, so an occurrence never counts twice for
+    /// the same term, but variants do count towards document length. Has no
+    /// effect together with `fold_diacritics`, which replaces tokens instead.
+    pub fn folded_variants(mut self, index: bool) -> Self {
+        self.folded_variants = index;
+        self
     }
 
-    fn get_idf(&self, token: &str) -> f32 {
-        let n = self.entries.len() as f32;
-        let df = self
-            .tf
-            .values()
-            .filter(|kv| kv.contains_key(token))
-            .count()
-            .max(1) as f32;
+    /// Writes decimal number tokens in a canonical form, in documents and
+    /// queries alike, so numerically equal values match whatever their JSON
+    /// spelling: trailing zeros after the dot are dropped, and so is the dot
+    /// when nothing is left after it. `15.0` and `15` are both `15`, and
+    /// `4.70` is `4.7`. Digits before the dot are kept as written, so large
+    /// integers never lose precision.
+    pub fn normalize_numbers(mut self, normalize: bool) -> Self {
+        self.normalize_numbers = normalize;
+        self
+    }
+
+    /// Gives strings, numbers and bools inside arrays their own path with
+    /// their position appended, e.g. `tags.0` for the first tag, instead of
+    /// sharing the array's path, so `Query::boost("tags.0", 2.0)` favours
+    /// documents listing a tag first. The array's path still covers every
+    /// position, so `tags:` scopes, boosts and include lists on `tags` behave
+    /// as before. Objects and arrays inside arrays are flattened as usual, so
+    /// `items.name` keeps covering the names of an array of objects. Per-field
+    /// IDF counts each position as its own field.
+    pub fn positional_arrays(mut self, positional: bool) -> Self {
+        self.positional_arrays = positional;
+        self
+    }
 
-        return (n / df).ln();
+    /// Also index the keys of every indexed object as `FIELD_KEY_PREFIX` plus
+    /// the key's uppercased dotted path, e.g. `FIELD:DISCOUNT` or
+    /// `FIELD:AUTHOR.NAME`, so documents can be found by the fields they have.
+    /// Query them with the `field:` clause: `field:discount` matches documents
+    /// with a `discount` key, whatever its value. While this is on, `field` can
+    /// no longer be used to scope a query to a document field named "field".
+    /// Key tokens don't count toward document length, so length normalization
+    /// ranks documents as it would without them.
+    pub fn index_field_keys(mut self, index: bool) -> Self {
+        self.index_field_keys = index;
+        self
     }
 
-    fn calculate_tf_idf(&mut self, key: &u32, token: &str) -> f32 {
-        let tf = self.get_tf(key, token);
-        let idf = self.get_idf(token);
+    /// Entries nested deeper than this many objects and arrays fail to index,
+    /// so untrusted input can't exhaust the stack. Defaults to 128, the same
+    /// limit `serde_json` applies when parsing.
+    pub fn max_depth(mut self, depth: usize) -> Self {
+        self.max_depth = depth;
+        self
+    }
 
-        return tf * idf;
+    /// Sets the top-level key holding each document's id. Defaults to `"id"`.
+    pub fn id_field(mut self, field: &str) -> Self {
+        self.id_field = field.to_string();
+        self
     }
 
-    pub fn search(&mut self, query: &str) -> Result<Vec<(serde_json::Value, f32)>> {
-        if let Some(cached_result) = self.cached_results.get(query) {
-            if cached_result.expiration > std::time::Instant::now() {
-                return Ok(cached_result.result.clone());
-            }
+    /// Only index the given fields. An empty list indexes everything.
+    pub fn include_fields(mut self, fields: &[&str]) -> Self {
+        self.include_fields = fields.iter().map(|f| f.to_string()).collect();
+        self
+    }
+
+    /// Never index the given fields, even when they are included.
+    pub fn exclude_fields(mut self, fields: &[&str]) -> Self {
+        self.exclude_fields = fields.iter().map(|f| f.to_string()).collect();
+        self
+    }
+
+    fn is_excluded(&self, path: &str) -> bool {
+        self.exclude_fields.iter().any(|f| path_covers(f, path))
+    }
+
+    fn is_included(&self, path: &str) -> bool {
+        self.include_fields.is_empty() || self.include_fields.iter().any(|f| path_covers(f, path))
+    }
+
+    fn may_include_below(&self, path: &str) -> bool {
+        self.include_fields.is_empty()
+            || self
+                .include_fields
+                .iter()
+                .any(|f| path_covers(f, path) || path_covers(path, f))
+    }
+}
+
+/// Flattens `(entry, score)` results into a JSON array of entries, each with
+/// its score stored under `score_key`. Fails if an entry is not an object or
+/// already has a `score_key` field, rather than silently overwriting it.
+pub fn results_to_json(
+    results: &[(serde_json::Value, f32)],
+    score_key: &str,
+) -> Result<serde_json::Value> {
+    let mut hits = Vec::with_capacity(results.len());
+    for (entry, score) in results {
+        let mut object = entry
+            .as_object()
+            .context("Search result entry is not a JSON object")?
+            .clone();
+        if object.contains_key(score_key) {
+            bail!("Search result entry already has a `{}` field", score_key);
         }
 
-        let tokens = tokenizer::Lexer::new(query.chars().collect::<Vec<_>>().as_slice())
-            .into_iter()
-            .map(|x| x.to_ascii_uppercase())
-            .collect::<Vec<String>>();
+        object.insert(score_key.to_string(), serde_json::Value::from(*score));
+        hits.push(serde_json::Value::Object(object));
+    }
 
-        let mut result = self
-            .tf
-            .clone()
-            .keys()
-            .into_iter()
-            .map(|id| {
-                (
-                    id.clone(),
-                    tokens
-                        .iter()
-                        .map(|t| self.calculate_tf_idf(id, t))
-                        .sum::<f32>(),
-                )
+    Ok(serde_json::Value::Array(hits))
+}
+
+/// Writes results as CSV, one row per result in order: a header row with
+/// `fields` and then `score`, followed by each entry's `fields`, top-level keys
+/// or dotted paths, and its score. Strings are written as-is and other values
+/// as JSON; a missing or null field is an empty cell.
+pub fn results_to_csv<W: Write>(
+    results: &[SearchResult],
+    fields: &[&str],
+    writer: W,
+) -> Result<()> {
+    let mut writer = csv::Writer::from_writer(writer);
+    writer
+        .write_record(fields.iter().copied().chain(["score"]))
+        .context("Failed to write CSV header")?;
+
+    for result in results {
+        let mut record = fields
+            .iter()
+            .map(|field| match get_path(&result.entry, field) {
+                None | Some(serde_json::Value::Null) => String::new(),
+                Some(serde_json::Value::String(s)) => s.to_string(),
+                Some(value) => value.to_string(),
             })
-            .filter(|(_, score)| *score > 0.0)
-            .collect::<Vec<(u32, f32)>>();
-        result.sort_by(|(_, a), (_, b)| b.partial_cmp(a).unwrap());
+            .collect::<Vec<String>>();
+        record.push(result.score.to_string());
+        writer
+            .write_record(&record)
+            .with_context(|| format!("Failed to write CSV row for entry {}", result.id))?;
+    }
 
-        let mut entries = Vec::new();
-        for (id, score) in result {
-            let parsed_id = id as u64;
+    writer.flush().context("Failed to flush CSV output")?;
 
-            let entry = self
-                .entries
-                .iter()
-                .find(|e| e.get("id").unwrap().as_u64().unwrap() == parsed_id)
-                .unwrap()
-                .clone();
-            entries.push((entry, score));
+    Ok(())
+}
+
+/// The kind of JSON value a token was produced from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum ValueKind {
+    String,
+    Number,
+    Bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+struct FieldKey {
+    path: String,
+    kind: ValueKind,
+}
+
+type TermId = u32;
+/// Takes the place of a pruned token in `Database::tokens`, so the positions
+/// of the tokens around it stay as they were indexed.
+const PRUNED_TERM: TermId = TermId::MAX;
+
+#[derive(Debug, Clone, PartialEq)]
+struct QueryTerm {
+    token: String,
+    weight: f32,
+    field: Option<String>,
+    // terms in the same group are alternatives: a typed term, its synonyms
+    // and the other alternatives of a `|` clause
+    group: usize,
+    // terms sharing a phrase must occur next to each other, in term order
+    phrase: Option<usize>,
+}
+type FieldTf = (FieldKey, HashMap<TermId, u32>);
+/// Token counts per field, before tokens are interned.
+type FieldCounts = HashMap<FieldKey, HashMap<String, u32>>;
+
+/// An entry's tokens before they are interned.
+#[derive(Debug, Default)]
+struct AnalyzedFields {
+    /// Every token, in document order.
+    tokens: Vec<String>,
+    fields: FieldCounts,
+    /// The field of each indexed value and where its tokens are in `tokens`.
+    values: Vec<(FieldKey, Range<usize>)>,
+}
+
+#[derive(Debug)]
+struct AnalyzedEntry {
+    id: u32,
+    analyzed: AnalyzedFields,
+}
+
+/// Where one indexed value's tokens are in its document's token stream,
+/// `tokens[start..end]`, and which of the document's `field_tf` entries is
+/// its field.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ValueSpan {
+    field: u32,
+    start: u32,
+    end: u32,
+}
+
+/// How a single query term contributed to a document's score.
+#[derive(Debug, Clone, Serialize)]
+pub struct TermExplanation {
+    pub token: String,
+    /// The field the term was scoped to with `field:value` syntax, if any.
+    pub field: Option<String>,
+    pub tf: f32,
+    pub idf: f32,
+    /// Query-side weight, below 1.0 for synonym expansions.
+    pub weight: f32,
+    pub score: f32,
+}
+
+/// Breakdown of a document's score for a query, as returned by `search_explain`.
+#[derive(Debug, Clone, Serialize)]
+pub struct Explanation {
+    pub id: u32,
+    pub score: f32,
+    pub terms: Vec<TermExplanation>,
+}
+
+/// Why part of a query didn't make it into scoring.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum DropReason {
+    /// The text had no letters or digits left after normalization.
+    NoTokens,
+    /// The token is a stop word, see `Database::load_stop_words`.
+    StopWord,
+    /// The token appears in no indexed document.
+    OutOfVocabulary,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DroppedTerm {
+    /// The normalized token, or the raw query text for `NoTokens`.
+    pub text: String,
+    pub reason: DropReason,
+}
+
+/// What a query turned into, as returned by `diagnose`. Meant for figuring
+/// out why a query returns nothing.
+#[derive(Debug, Clone, Serialize)]
+pub struct QueryDiagnostics {
+    /// Query tokens after normalization, synonyms not included.
+    pub tokens: Vec<String>,
+    pub in_vocabulary: Vec<String>,
+    pub dropped: Vec<DroppedTerm>,
+    /// Documents containing at least one of the query terms.
+    pub candidate_count: usize,
+}
+
+/// How the terms of a query combine to decide which documents match.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum Operator {
+    /// Documents containing any query term match.
+    #[default]
+    Or,
+    /// Documents must contain every query term, or one of its synonyms.
+    And,
+}
+
+/// How per-term contributions are combined into a document's score.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub enum ScoreMode {
+    /// Sum TF-IDF over the whole document.
+    #[default]
+    Sum,
+    /// Score each field on its own and keep the best one, so a strong match in
+    /// a single field beats weak matches spread over many.
+    BestField,
+    /// Like `BestField`, plus `tie_breaker` times the scores of the other
+    /// fields. A tie breaker of 0.0 is `BestField`, 1.0 sums the fields.
+    DisMax { tie_breaker: f32 },
+    /// Ignore term frequency and IDF and add up the weights of the distinct
+    /// query tokens the document contains. Cheap, and suits tag-like data.
+    Overlap,
+    /// Shared distinct tokens divided by the distinct tokens in the query and
+    /// the document together; 1.0 means both hold exactly the same tokens.
+    Jaccard,
+    /// The fraction of the document's tokens that are query tokens, counting
+    /// every occurrence, so a short document about the query beats a long one
+    /// that mentions it in passing. Ignores IDF and weights.
+    Coverage,
+}
+
+/// Results sharing one value of the field passed to `search_grouped`.
+#[derive(Debug, Clone, Serialize)]
+pub struct ResultGroup {
+    /// The field's value, or `None` for entries where it's missing or null.
+    pub key: Option<serde_json::Value>,
+    pub results: Vec<(serde_json::Value, f32)>,
+}
+
+/// How a token's document frequency `df` out of `n` documents becomes its
+/// IDF. Every variant is 0.0 for a token no document contains.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum IdfVariant {
+    /// `log(n / df)`.
+    #[default]
+    Standard,
+    /// `log(1 + n / df)`, which stays positive for tokens in every document.
+    Smoothed,
+    /// `log((n - df) / df)`, floored at 0.0 for tokens in half the documents
+    /// or more.
+    Probabilistic,
+}
+
+/// The logarithm used for IDF.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum LogBase {
+    #[default]
+    E,
+    Two,
+    Ten,
+}
+
+impl LogBase {
+    fn log(self, x: f32) -> f32 {
+        match self {
+            LogBase::E => x.ln(),
+            LogBase::Two => x.log2(),
+            LogBase::Ten => x.log10(),
         }
+    }
+}
 
-        self.cached_results.insert(
-            query.to_string(),
-            CachedResult {
-                result: entries.clone(),
-                expiration: std::time::Instant::now() + std::time::Duration::from_secs(60),
-            },
-        );
+/// A search result, as returned by `search_results`. Serializes as an object
+/// rather than the `[entry, score]` arrays tuples turn into.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SearchResult {
+    pub entry: serde_json::Value,
+    pub score: f32,
+    pub id: u32,
+}
 
-        Ok(entries)
+/// A search result along with how much of the query it matched.
+#[derive(Debug, Clone, Serialize)]
+pub struct DetailedResult {
+    pub id: u32,
+    pub entry: serde_json::Value,
+    pub score: f32,
+    /// Distinct query terms found in the entry. Synonyms don't count, and a
+    /// `field:value` term only counts when found in that field.
+    pub matched_terms: usize,
+    pub total_query_terms: usize,
+    /// Where each matched query token, synonyms included, occurs in the
+    /// document's token stream: every field's tokens, in key order, as
+    /// indexed. Field scopes are ignored here.
+    pub matches: HashMap<String, Vec<usize>>,
+}
+
+/// Vocabulary health metrics, as returned by `vocab_stats`. Document
+/// frequency is the number of indexed documents a token appears in.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct VocabStats {
+    pub unique_tokens: usize,
+    pub mean_document_frequency: f32,
+    pub median_document_frequency: f32,
+    /// Tokens found in a single document. A sudden jump often means
+    /// something is being tokenized that shouldn't be, like ids or hashes.
+    pub hapax_tokens: usize,
+}
+
+type Derive = dyn Fn(&serde_json::Value) -> Option<String> + Send + Sync;
+
+/// A field computed from each entry at index time, see
+/// `Database::add_derived_field`.
+struct DerivedField {
+    name: String,
+    derive: Box<Derive>,
+}
+
+fn serialize_analyzer<A, S>(analyzer: &A, serializer: S) -> Result<S::Ok, S::Error>
+where
+    A: std::ops::Deref<Target = dyn Analyzer>,
+    S: Serializer,
+{
+    analyzer.builtin().serialize(serializer)
+}
+
+fn serialize_field_analyzers<S: Serializer>(
+    analyzers: &HashMap<String, Box<dyn Analyzer>>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_map(
+        analyzers
+            .iter()
+            .map(|(field, analyzer)| (field, analyzer.builtin())),
+    )
+}
+
+fn serialize_derived_fields<S: Serializer>(
+    fields: &[DerivedField],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(fields.iter().map(|field| &field.name))
+}
+
+impl std::fmt::Debug for DerivedField {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DerivedField")
+            .field("name", &self.name)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug)]
+struct CachedResult {
+    result: Vec<(serde_json::Value, f32)>,
+    expiration: Instant,
+    generation: u64,
+}
+
+/// Serializes the full searchable state: entries, the index and settings.
+/// The result cache is transient and never serialized, and lookup maps that
+/// can be derived from the rest are rebuilt on deserialize. Documents moved to
+/// a `DocumentStore` are not part of the snapshot. Built-in analyzers are
+/// recorded and rebuilt; a snapshot with custom analyzers or derived fields
+/// fails to deserialize, load it with `load_index_with` instead.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(try_from = "DatabaseSnapshot")]
+pub struct Database {
+    pub entries: Vec<serde_json::Value>,
+    #[serde(skip)]
+    positions: HashMap<u32, usize>,
+    string_ids: HashMap<String, u32>,
+    /// The highest id assigned or seen so far; ids handed out by the database
+    /// count up from it.
+    last_id: u32,
+    tokens: HashMap<u32, Vec<TermId>>,
+    // each document's values in `tokens`, so phrases never span two values
+    values: HashMap<u32, Vec<ValueSpan>>,
+
+    // every distinct token is stored once in `terms`, everything else refers
+    // to it by its position
+    terms: Vec<String>,
+    #[serde(skip)]
+    term_ids: HashMap<String, TermId>,
+    tf: HashMap<u32, HashMap<TermId, u32>>,
+    // total token count per document, the length of its `tokens` without
+    // field key tokens, pruned tokens included
+    #[serde(skip)]
+    doc_len: HashMap<u32, u32>,
+    #[serde(skip)]
+    total_len: u64,
+    df: HashMap<TermId, u32>,
+    field_tf: HashMap<u32, Vec<FieldTf>>,
+    // per field path: how many documents have that field, and how many have
+    // each term in it
+    #[serde(skip)]
+    field_docs: HashMap<String, u32>,
+    #[serde(skip)]
+    field_df: HashMap<String, HashMap<TermId, u32>>,
+    /// Content hash of each document as last indexed by `tokenize_entries`.
+    #[serde(skip)]
+    indexed_hashes: HashMap<u32, u64>,
+    // analyzers are saved as their `BuiltinAnalyzer`, derived fields by name
+    #[serde(serialize_with = "serialize_analyzer")]
+    analyzer: Box<dyn Analyzer>,
+    #[serde(serialize_with = "serialize_field_analyzers")]
+    field_analyzers: HashMap<String, Box<dyn Analyzer>>,
+    #[serde(serialize_with = "serialize_derived_fields")]
+    derived_fields: Vec<DerivedField>,
+    #[serde(skip)]
+    store: Option<Box<dyn DocumentStore>>,
+    #[serde(skip)]
+    cached_results: HashMap<String, CachedResult>,
+    #[serde(skip)]
+    generation: u64,
+    cache_ttl: Duration,
+    tie_breakers: Vec<SortKey>,
+    tie_policy: TiePolicy,
+    demotions: Vec<(Filter, f32)>,
+    config: IndexConfig,
+    synonyms: HashMap<String, Vec<String>>,
+    stop_words: HashSet<String>,
+    synonym_weight: f32,
+    score_mode: ScoreMode,
+    idf_variant: IdfVariant,
+    log_base: LogBase,
+    default_operator: Operator,
+    empty_query: EmptyQuery,
+    max_results: usize,
+    max_features: Option<usize>,
+    length_normalization: f32,
+    idf_floor: f32,
+    max_query_length: usize,
+    max_query_tokens: usize,
+    per_field_idf: bool,
+}
+
+#[derive(Deserialize)]
+struct DatabaseSnapshot {
+    entries: Vec<serde_json::Value>,
+    string_ids: HashMap<String, u32>,
+    /// The highest id assigned or seen so far; ids handed out by the database
+    /// count up from it.
+    last_id: u32,
+    tokens: HashMap<u32, Vec<TermId>>,
+    values: HashMap<u32, Vec<ValueSpan>>,
+    terms: Vec<String>,
+    tf: HashMap<u32, HashMap<TermId, u32>>,
+    df: HashMap<TermId, u32>,
+    field_tf: HashMap<u32, Vec<FieldTf>>,
+    cache_ttl: Duration,
+    tie_breakers: Vec<SortKey>,
+    tie_policy: TiePolicy,
+    demotions: Vec<(Filter, f32)>,
+    config: IndexConfig,
+    synonyms: HashMap<String, Vec<String>>,
+    stop_words: HashSet<String>,
+    synonym_weight: f32,
+    score_mode: ScoreMode,
+    idf_variant: IdfVariant,
+    log_base: LogBase,
+    default_operator: Operator,
+    empty_query: EmptyQuery,
+    max_results: usize,
+    max_features: Option<usize>,
+    length_normalization: f32,
+    idf_floor: f32,
+    max_query_length: usize,
+    max_query_tokens: usize,
+    per_field_idf: bool,
+    analyzer: Option<BuiltinAnalyzer>,
+    field_analyzers: HashMap<String, Option<BuiltinAnalyzer>>,
+    derived_fields: Vec<String>,
+}
+
+impl TryFrom<DatabaseSnapshot> for Database {
+    type Error = anyhow::Error;
+
+    fn try_from(snapshot: DatabaseSnapshot) -> Result<Self> {
+        Self::restore(snapshot, |_| {})
+    }
+}
+
+/// Checks that the analyzer a database was saved with is the one it has now.
+/// `None` stands for a custom analyzer.
+fn check_analyzer(
+    what: &str,
+    saved: Option<BuiltinAnalyzer>,
+    current: Option<BuiltinAnalyzer>,
+) -> Result<()> {
+    match saved {
+        Some(_) if current != saved => bail!(
+            "The {} was saved as {:?}, but is now {}",
+            what,
+            saved,
+            current.map_or("a custom one".to_string(), |a| format!("{:?}", a))
+        ),
+        None if current.is_some() => bail!(
+            "The index was built with a custom {}, pass it to `Database::load_index_with`",
+            what
+        ),
+        _ => Ok(()),
+    }
+}
+
+impl Default for Database {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Database {
+    pub fn new() -> Self {
+        Self::with_config(IndexConfig::default())
+    }
+
+    /// Like `new`, but with room for `n` documents in the entry list and the
+    /// per-document index maps, to avoid reallocating during a bulk load of a
+    /// known size.
+    pub fn with_capacity(n: usize) -> Self {
+        let mut db = Self::new();
+        db.entries.reserve(n);
+        db.positions.reserve(n);
+        db.tokens.reserve(n);
+        db.values.reserve(n);
+        db.tf.reserve(n);
+        db.doc_len.reserve(n);
+        db.field_tf.reserve(n);
+
+        db
+    }
+
+    pub fn with_config(config: IndexConfig) -> Self {
+        Self::with_analyzer(config, Box::new(LexerAnalyzer))
+    }
+
+    /// Like `with_config`, but tokenizes documents and queries with `analyzer`
+    /// instead of the default `LexerAnalyzer`.
+    pub fn with_analyzer(config: IndexConfig, analyzer: Box<dyn Analyzer>) -> Self {
+        Self {
+            entries: Vec::new(),
+            positions: HashMap::new(),
+            string_ids: HashMap::new(),
+            last_id: 0,
+            tokens: HashMap::new(),
+            values: HashMap::new(),
+            terms: Vec::new(),
+            term_ids: HashMap::new(),
+            tf: HashMap::new(),
+            doc_len: HashMap::new(),
+            total_len: 0,
+            df: HashMap::new(),
+            field_tf: HashMap::new(),
+            field_docs: HashMap::new(),
+            field_df: HashMap::new(),
+            indexed_hashes: HashMap::new(),
+            analyzer,
+            field_analyzers: HashMap::new(),
+            derived_fields: Vec::new(),
+            store: None,
+            cached_results: HashMap::new(),
+            generation: 0,
+            cache_ttl: DEFAULT_CACHE_TTL,
+            tie_breakers: Vec::new(),
+            tie_policy: TiePolicy::default(),
+            demotions: Vec::new(),
+            config,
+            synonyms: HashMap::new(),
+            stop_words: HashSet::new(),
+            synonym_weight: 1.0,
+            score_mode: ScoreMode::default(),
+            idf_variant: IdfVariant::default(),
+            log_base: LogBase::default(),
+            default_operator: Operator::default(),
+            empty_query: EmptyQuery::default(),
+            max_results: DEFAULT_MAX_RESULTS,
+            max_features: None,
+            length_normalization: 1.0,
+            idf_floor: 0.0,
+            max_query_length: DEFAULT_MAX_QUERY_LENGTH,
+            max_query_tokens: DEFAULT_MAX_QUERY_TOKENS,
+            per_field_idf: false,
+        }
+    }
+
+    /// Sets how long `search` results stay cached. Defaults to 60 seconds.
+    /// Entries already in the cache keep the expiration they were stored with.
+    pub fn set_cache_ttl(&mut self, ttl: Duration) {
+        self.cache_ttl = ttl;
+    }
+
+    /// Makes the per-field scores of `ScoreMode::BestField` and `DisMax` use
+    /// IDF computed within each field, with `n` the number of documents that
+    /// have the field and `df` the number of those with the token in it. A
+    /// token that is common in descriptions but rare in titles then keeps its
+    /// weight in titles. `Sum` and the other modes always use corpus-wide IDF.
+    pub fn set_per_field_idf(&mut self, enabled: bool) {
+        self.per_field_idf = enabled;
+        self.invalidate();
+    }
+
+    /// Selects the IDF formula and the base of its logarithm. The default is
+    /// `IdfVariant::Standard` with `LogBase::E`, i.e. `ln(n / df)`.
+    pub fn set_idf(&mut self, variant: IdfVariant, base: LogBase) {
+        self.idf_variant = variant;
+        self.log_base = base;
+        self.invalidate();
+    }
+
+    /// Sets how strongly TF is normalized by document length: term counts are
+    /// divided by `slope * length + (1 - slope) * average length`. 1.0, the
+    /// default, divides by the document's own length; 0.0 by the average
+    /// length, ignoring length entirely; values in between penalize long
+    /// documents only partly. Clamped to 0.0..=1.0.
+    pub fn set_length_normalization(&mut self, slope: f32) {
+        self.length_normalization = slope.clamp(0.0, 1.0);
+        self.invalidate();
+    }
+
+    /// Gives every indexed token an IDF of at least `floor`, so a token found
+    /// in every document still counts a little instead of scoring 0.0. A
+    /// matched term then always contributes at least its TF times `floor`,
+    /// which keeps documents ranked by how much they use the term, and keeps
+    /// them above the `score > 0.0` cut `search` makes that would otherwise
+    /// drop a query made only of ubiquitous tokens to no results. Unlike
+    /// `IdfVariant::Smoothed`, rarer tokens are unaffected as long as their IDF
+    /// is above the floor. Tokens the index doesn't have still score 0.0.
+    /// Defaults to 0.0, no floor; negative values are treated as 0.0.
+    pub fn set_idf_floor(&mut self, floor: f32) {
+        self.idf_floor = floor.max(0.0);
+        self.invalidate();
+    }
+
+    /// Queries longer than `max_length` bytes, or with more than `max_tokens`
+    /// tokens before synonym expansion, are rejected with an error instead of
+    /// being scored. The defaults are 4096 bytes and 256 tokens. `diagnose`
+    /// ignores the limits.
+    pub fn set_query_limits(&mut self, max_length: usize, max_tokens: usize) {
+        self.max_query_length = max_length;
+        self.max_query_tokens = max_tokens;
+        self.invalidate();
+    }
+
+    /// Caps how many results a search returns, 1000 by default, so a broad
+    /// query can't clone the whole corpus. The cap applies before any paging
+    /// by the caller: results ranked below it can't be reached by asking for a
+    /// later page. Use `usize::MAX` for no cap.
+    pub fn set_max_results(&mut self, max: usize) {
+        self.max_results = max;
+        self.invalidate();
+    }
+
+    /// Caps the vocabulary at `max` tokens, like scikit-learn's
+    /// `max_features`. Every `tokenize_entries` call then keeps the tokens with
+    /// the highest document frequency, ties broken alphabetically, and removes
+    /// the rest from every indexed document, so they no longer match. Once
+    /// pruned, a token is gone from the documents indexed so far even if it
+    /// later becomes frequent; `rebuild` ranks everything afresh. Documents
+    /// indexed by other means, such as `index_stream`, aren't pruned until the
+    /// next `tokenize_entries`. Document lengths and token positions still
+    /// count pruned tokens, so the tokens on either side of one never become
+    /// neighbours.
+    pub fn set_max_features(&mut self, max: Option<usize>) {
+        self.max_features = max;
+        self.invalidate();
+    }
+
+    /// Drops all but the `max` tokens with the highest document frequency.
+    /// Returns whether anything was dropped.
+    fn prune_vocabulary(&mut self, max: usize) -> bool {
+        if self.df.len() <= max {
+            return false;
+        }
+
+        let mut ranked = self.df.iter().map(|(t, df)| (*t, *df)).collect::<Vec<_>>();
+        ranked.sort_by(|(a, a_df), (b, b_df)| {
+            b_df.cmp(a_df)
+                .then_with(|| self.terms[*a as usize].cmp(&self.terms[*b as usize]))
+        });
+        let dropped = ranked[max..]
+            .iter()
+            .map(|(term, _)| *term)
+            .collect::<HashSet<TermId>>();
+
+        let kept = |term: &TermId| !dropped.contains(term);
+        let terms = &self.terms;
+        for tokens in self.tokens.values_mut() {
+            // field key tokens come last, so dropping them moves no other token
+            // and keeps them out of the document length when it is recomputed
+            tokens.retain(|term| {
+                *term == PRUNED_TERM
+                    || kept(term)
+                    || !terms[*term as usize].starts_with(FIELD_KEY_PREFIX)
+            });
+            for term in tokens.iter_mut().filter(|term| !kept(term)) {
+                *term = PRUNED_TERM;
+            }
+        }
+        for tf in self.tf.values_mut() {
+            tf.retain(|term, _| kept(term));
+        }
+        // fields left without tokens stay, so field document counts still
+        // match when the document is removed
+        for fields in self.field_tf.values_mut() {
+            for (_, tf) in fields.iter_mut() {
+                tf.retain(|term, _| kept(term));
+            }
+        }
+        self.df.retain(|term, _| kept(term));
+        for df in self.field_df.values_mut() {
+            df.retain(|term, _| kept(term));
+        }
+        self.compact();
+
+        true
+    }
+
+    /// Sets whether a document needs any (`Or`, the default) or all (`And`) of
+    /// the query terms to match.
+    pub fn set_default_operator(&mut self, operator: Operator) {
+        self.default_operator = operator;
+        self.invalidate();
+    }
+
+    /// Sets what an empty or all-whitespace query returns, e.g. every document
+    /// for a browsable list view. Queries that only hold punctuation still
+    /// return nothing.
+    pub fn set_empty_query(&mut self, empty_query: EmptyQuery) {
+        self.empty_query = empty_query;
+        self.invalidate();
+    }
+
+    /// The results of an empty query, limited to entries matching `filter`.
+    fn rank_empty_query(&self, filter: Option<&Filter>) -> Result<Vec<(u32, f32)>> {
+        let passes = |entry: &serde_json::Value| filter.is_none_or(|f| f.matches(entry));
+        match &self.empty_query {
+            EmptyQuery::None => Ok(Vec::new()),
+            EmptyQuery::All if filter.is_none() => self.rank_ids(self.tf.keys(), None, |_| 1.0),
+            EmptyQuery::All => self.rank_with(
+                self.tf.keys(),
+                None,
+                |_| 1.0,
+                |_, entry, score| passes(entry).then_some(score),
+            ),
+            EmptyQuery::AllSortedBy(key) => {
+                self.ensure_indexed()?;
+
+                let mut result = Vec::new();
+                for id in self.tf.keys() {
+                    let entry = self
+                        .get(*id)
+                        .with_context(|| format!("Failed to find entry {}", id))?;
+                    if passes(&entry) {
+                        result.push((*id, entry));
+                    }
+                }
+                result.sort_by(|a, b| key.compare(&a.1, &b.1).then_with(|| a.0.cmp(&b.0)));
+                result.truncate(self.max_results);
+
+                Ok(result.into_iter().map(|(id, _)| (id, 1.0)).collect())
+            }
+        }
+    }
+
+    /// Whether the document has enough of the query terms to be a candidate
+    /// under the default operator.
+    fn is_candidate(&self, id: &u32, terms: &[QueryTerm]) -> bool {
+        let has_terms = match self.default_operator {
+            Operator::Or => true,
+            Operator::And => terms.iter().all(|term| {
+                terms
+                    .iter()
+                    .filter(|t| t.group == term.group)
+                    .any(|t| self.get_term_tf(id, t) > 0.0)
+            }),
+        };
+
+        has_terms && self.has_phrases(id, terms)
+    }
+
+    /// Whether the document contains every phrase in `terms`: its tokens one
+    /// after the other within a single value, of the phrase's field if scoped.
+    fn has_phrases(&self, id: &u32, terms: &[QueryTerm]) -> bool {
+        let mut phrases = terms
+            .iter()
+            .filter_map(|t| t.phrase)
+            .collect::<Vec<usize>>();
+        phrases.dedup();
+        if phrases.is_empty() {
+            return true;
+        }
+
+        let stream = self.tokens.get(id).map_or(&[][..], Vec::as_slice);
+        let fields = self.field_tf.get(id).map_or(&[][..], Vec::as_slice);
+        let values = self.values.get(id).map_or(&[][..], Vec::as_slice);
+        phrases.into_iter().all(|phrase| {
+            let phrase = terms
+                .iter()
+                .filter(|t| t.phrase == Some(phrase))
+                .collect::<Vec<&QueryTerm>>();
+            let Some(ids) = phrase
+                .iter()
+                .map(|t| self.term_id(&t.token))
+                .collect::<Option<Vec<TermId>>>()
+            else {
+                return false;
+            };
+
+            let scope = phrase[0].field.as_deref();
+            values.iter().any(|value| {
+                let in_scope = scope.is_none_or(|scope| {
+                    fields
+                        .get(value.field as usize)
+                        .is_some_and(|(field, _)| path_covers(scope, &field.path))
+                });
+                in_scope
+                    && stream
+                        .get(value.start as usize..value.end as usize)
+                        .is_some_and(|tokens| tokens.windows(ids.len()).any(|w| w == ids))
+            })
+        })
+    }
+
+    pub fn set_score_mode(&mut self, mode: ScoreMode) {
+        self.score_mode = mode;
+        self.invalidate();
+    }
+
+    /// Sets the keys used to order results with equal scores. Ties that remain
+    /// after every key are always broken by ascending id.
+    pub fn set_tie_breakers(&mut self, keys: Vec<SortKey>) {
+        self.tie_breakers = keys;
+        self.invalidate();
+    }
+
+    /// Sets how results with equal scores are ordered. Either way the order is
+    /// stable across calls, so pages cut from it don't overlap.
+    pub fn set_tie_policy(&mut self, policy: TiePolicy) {
+        self.tie_policy = policy;
+        self.invalidate();
+    }
+
+    /// Multiplies the score of every entry matching `filter` by `factor`. Unlike
+    /// filtering, demoted entries still match, they just rank lower. An entry
+    /// matching several demotions gets all of their factors. Fails unless
+    /// `factor` is in (0, 1]: a zero or negative factor would drop entries from
+    /// the results instead of demoting them.
+    pub fn add_demotion(&mut self, filter: Filter, factor: f32) -> Result<()> {
+        if !(factor > 0.0 && factor <= 1.0) {
+            bail!("Demotion factor must be in (0, 1], got {}", factor);
+        }
+
+        self.demotions.push((filter, factor));
+        self.invalidate();
+        Ok(())
+    }
+
+    fn compare_results(
+        &self,
+        a: (&serde_json::Value, f32),
+        b: (&serde_json::Value, f32),
+    ) -> Ordering {
+        b.1.total_cmp(&a.1).then_with(|| {
+            if self.tie_policy == TiePolicy::LowestId {
+                return Ordering::Equal;
+            }
+
+            self.tie_breakers
+                .iter()
+                .map(|key| key.compare(a.0, b.0))
+                .find(|ord| ord.is_ne())
+                .unwrap_or(Ordering::Equal)
+        })
+    }
+
+    pub fn add(&mut self, entry: serde_json::Value) {
+        if let Some(id) = self.assign_id(&entry) {
+            self.positions.insert(id, self.entries.len());
+        }
+        self.entries.push(entry);
+        self.invalidate();
+    }
+
+    /// A counter bumped by every change that can affect search results:
+    /// adding, indexing or removing documents and changing settings. Cached
+    /// results are only served for the generation they were computed in.
+    /// Editing `entries` directly isn't tracked.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    fn invalidate(&mut self) {
+        self.generation += 1;
+        self.cached_results.clear();
+    }
+
+    /// Returns the internal id of the document whose id field holds the string
+    /// `key`. Use `get` to fetch the entry itself.
+    pub fn id_for_key(&self, key: &str) -> Option<u32> {
+        self.string_ids.get(key).copied()
+    }
+
+    /// Serializes `value` into a JSON object, assigns it the next id (replacing
+    /// any existing value in the id field) and adds it to the database. Ids
+    /// count up from the highest one the database has seen, so they stay
+    /// unique after removals or documents moved to a store.
+    pub fn add_typed<T: Serialize>(&mut self, value: &T) -> Result<u32> {
+        let mut entry = serde_json::to_value(value).context("Failed to serialize entry")?;
+        let object = entry
+            .as_object_mut()
+            .context("Entry must serialize to a JSON object")?;
+        let id = self.next_id();
+        object.insert(self.config.id_field.clone(), serde_json::Value::from(id));
+        self.add(entry);
+
+        Ok(id)
+    }
+
+    /// Reads every CSV file in `paths`, in order, mapping each row with `map`
+    /// and adding it with `add_typed`, so ids keep counting up across files.
+    /// Bad rows are reported per file and skipped; a file that can't be opened
+    /// stops the load, keeping whatever earlier files added.
+    pub fn load_csv_files<P, R, T, F>(&mut self, paths: &[P], map: F) -> Result<Vec<LoadReport>>
+    where
+        P: AsRef<Path>,
+        R: DeserializeOwned,
+        T: Serialize,
+        F: Fn(R) -> T,
+    {
+        let mut reports = Vec::new();
+        for path in paths {
+            let path = path.as_ref();
+            let mut reader = csv::Reader::from_path(path)
+                .with_context(|| format!("Failed to read file {}", path.display()))?;
+
+            let mut report = LoadReport {
+                path: path.to_path_buf(),
+                rows: 0,
+                errors: Vec::new(),
+            };
+            for (line, record) in reader.deserialize::<R>().enumerate() {
+                let added = record
+                    .map_err(anyhow::Error::from)
+                    .and_then(|record| self.add_typed(&map(record)));
+                match added {
+                    Ok(_) => report.rows += 1,
+                    // line 1 is the header
+                    Err(err) => report.errors.push(format!("line {}: {:#}", line + 2, err)),
+                }
+            }
+            reports.push(report);
+        }
+
+        Ok(reports)
+    }
+
+    fn get_tokens(&self, val: &serde_json::Value, path: &str) -> Vec<(FieldKey, String)> {
+        let field = |kind| FieldKey {
+            path: path.to_string(),
+            kind,
+        };
+
+        match val {
+            serde_json::Value::Object(map) => {
+                let mut tokens = Vec::new();
+                for (key, value) in map {
+                    let child_path = if path.is_empty() {
+                        key.to_string()
+                    } else {
+                        format!("{}.{}", path, key)
+                    };
+                    if self.config.is_excluded(&child_path)
+                        || !self.config.may_include_below(&child_path)
+                    {
+                        continue;
+                    }
+
+                    tokens.append(&mut self.get_tokens(value, &child_path));
+                }
+                tokens
+            }
+            serde_json::Value::Array(arr) => {
+                let mut tokens = Vec::new();
+                for (position, value) in arr.iter().enumerate() {
+                    let is_scalar = !(value.is_object() || value.is_array());
+                    if self.config.positional_arrays && is_scalar {
+                        let child_path = if path.is_empty() {
+                            position.to_string()
+                        } else {
+                            format!("{}.{}", path, position)
+                        };
+                        tokens.append(&mut self.get_tokens(value, &child_path));
+                    } else {
+                        tokens.append(&mut self.get_tokens(value, path));
+                    }
+                }
+                tokens
+            }
+            _ if !self.config.is_included(path) => Vec::new(),
+            serde_json::Value::String(s) => vec![(field(ValueKind::String), s.to_string())],
+            serde_json::Value::Number(n) => vec![(field(ValueKind::Number), n.to_string())],
+            serde_json::Value::Bool(b) => vec![(field(ValueKind::Bool), b.to_string())],
+            // documents without any tokens are still indexed, they just never match
+            serde_json::Value::Null => Vec::new(),
+        }
+    }
+
+    /// Collects the `FIELD:` token of every key below `path` that would be
+    /// indexed, along with the key's own field.
+    fn get_field_keys(&self, val: &serde_json::Value, path: &str) -> Vec<(FieldKey, String)> {
+        match val {
+            serde_json::Value::Object(map) => {
+                let mut keys = Vec::new();
+                for (key, value) in map {
+                    let child_path = if path.is_empty() {
+                        key.to_string()
+                    } else {
+                        format!("{}.{}", path, key)
+                    };
+                    if self.config.is_excluded(&child_path)
+                        || !self.config.may_include_below(&child_path)
+                    {
+                        continue;
+                    }
+
+                    let field = FieldKey {
+                        path: child_path.clone(),
+                        kind: ValueKind::String,
+                    };
+                    keys.push((field, field_key_token(&child_path)));
+                    keys.append(&mut self.get_field_keys(value, &child_path));
+                }
+                keys
+            }
+            serde_json::Value::Array(arr) => arr
+                .iter()
+                .flat_map(|value| self.get_field_keys(value, path))
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    fn analyze_entry(&self, entry: &serde_json::Value) -> Result<AnalyzedEntry> {
+        let analyzed = self.analyze_fields(entry)?;
+
+        /*
+                    let tokens = tokenizer::Lexer::new(query.chars().collect::<Vec<_>>().as_slice())
+                        .into_iter()
+                        .map(|x| x.to_ascii_uppercase())
+                        .collect::<Vec<String>>();
+        */
+
+        let id_field = &self.config.id_field;
+        let entry_id_key = entry
+            .get(id_field)
+            .with_context(|| format!("Failed to get id field `{}`", id_field))?;
+        let id = self.entry_id(entry).with_context(|| {
+            format!(
+                "Id field `{}` must be an unsigned integer or a string, got {}",
+                id_field, entry_id_key
+            )
+        })?;
+
+        Ok(AnalyzedEntry { id, analyzed })
+    }
+
+    /// Every token of `entry`, in document order, the per-field counts and
+    /// where each value's tokens are.
+    fn analyze_fields(&self, entry: &serde_json::Value) -> Result<AnalyzedFields> {
+        // get_tokens recurses once per level, so bound the depth before walking
+        let max_depth = self.config.max_depth;
+        if nesting_depth(entry, max_depth) > max_depth {
+            bail!("Entry nests deeper than the maximum depth of {}", max_depth);
+        }
+
+        let mut analyzed = AnalyzedFields::default();
+        let derived = self.derived_fields.iter().filter_map(|derived| {
+            let field = FieldKey {
+                path: derived.name.to_string(),
+                kind: ValueKind::String,
+            };
+            (derived.derive)(entry).map(|text| (field, text))
+        });
+        for (field, x) in self.get_tokens(entry, "").into_iter().chain(derived) {
+            let analyzer = self.analyzer_for(&field.path);
+            let mut tokens = self.analyze_text(analyzer, &x);
+            // variants come after the value's own tokens, outside its span, so
+            // they never form phrases
+            let start = analyzed.tokens.len();
+            analyzed
+                .values
+                .push((field.clone(), start..start + tokens.len()));
+            if self.config.folded_variants && !self.config.fold_diacritics {
+                let mut folded = analyzer.analyze(&fold_diacritics(&x));
+                self.normalize_numbers(&mut folded);
+                self.drop_stop_words(&mut folded);
+                let variants = if folded.len() == tokens.len() {
+                    folded
+                        .into_iter()
+                        .zip(&tokens)
+                        .filter(|(variant, token)| variant != *token)
+                        .map(|(variant, _)| variant)
+                        .collect()
+                } else {
+                    // folding changed how the text splits, so variants can't be
+                    // paired with their originals; keep the ones that are new
+                    folded
+                        .into_iter()
+                        .filter(|variant| !tokens.contains(variant))
+                        .collect::<Vec<_>>()
+                };
+                tokens.extend(variants);
+            }
+
+            let field_tf = analyzed.fields.entry(field).or_default();
+            for token in tokens {
+                *field_tf.entry(token.clone()).or_insert(0) += 1;
+                analyzed.tokens.push(token);
+            }
+        }
+        if self.config.index_field_keys {
+            for (field, token) in self.get_field_keys(entry, "") {
+                *analyzed
+                    .fields
+                    .entry(field)
+                    .or_default()
+                    .entry(token.clone())
+                    .or_insert(0) += 1;
+                analyzed.tokens.push(token);
+            }
+        }
+
+        Ok(analyzed)
+    }
+
+    fn index_document(&mut self, analyzed: AnalyzedEntry) {
+        let AnalyzedEntry {
+            id,
+            analyzed:
+                AnalyzedFields {
+                    tokens: entry_tokens,
+                    fields,
+                    values,
+                },
+        } = analyzed;
+        self.unindex_document(id);
+        self.generation += 1;
+
+        let entry_tokens = entry_tokens
+            .iter()
+            .map(|token| self.intern(token))
+            .collect::<Vec<TermId>>();
+        let mut fields = fields
+            .into_iter()
+            .map(|(field, tf)| {
+                let tf = tf
+                    .into_iter()
+                    .map(|(token, count)| (self.intern(&token), count))
+                    .collect::<HashMap<TermId, u32>>();
+                (field, tf)
+            })
+            .collect::<Vec<FieldTf>>();
+        // per-field scores are summed in this order, so keep it independent of
+        // HashMap iteration to get bit-identical scores on every run
+        fields.sort_by(|a, b| a.0.cmp(&b.0));
+        let values = values
+            .into_iter()
+            .filter(|(_, range)| !range.is_empty())
+            .filter_map(|(field, range)| {
+                let field = fields.binary_search_by(|(f, _)| f.cmp(&field)).ok()?;
+                Some(ValueSpan {
+                    field: field as u32,
+                    start: range.start as u32,
+                    end: range.end as u32,
+                })
+            })
+            .collect::<Vec<ValueSpan>>();
+
+        let mut tf = HashMap::new();
+        for token in &entry_tokens {
+            let count = tf.entry(*token).or_insert(0);
+            *count += 1;
+        }
+        for token in tf.keys() {
+            *self.df.entry(*token).or_insert(0) += 1;
+        }
+
+        let length = self.content_length(&entry_tokens);
+        self.doc_len.insert(id, length);
+        self.total_len += length as u64;
+        self.tokens.insert(id, entry_tokens);
+        self.values.insert(id, values);
+        self.tf.insert(id, tf);
+        self.field_tf.insert(id, fields);
+        self.count_fields(id, true);
+    }
+
+    /// Adds the document's fields to (or, with `add` false, removes them from)
+    /// the per-field document frequencies.
+    fn count_fields(&mut self, id: u32, add: bool) {
+        let Some(fields) = self.field_tf.get(&id) else {
+            return;
+        };
+
+        let mut paths: HashMap<&str, HashSet<TermId>> = HashMap::new();
+        for (field, tf) in fields {
+            paths
+                .entry(field.path.as_str())
+                .or_default()
+                .extend(tf.keys());
+        }
+
+        for (path, terms) in paths {
+            let docs = self.field_docs.entry(path.to_string()).or_insert(0);
+            let df = self.field_df.entry(path.to_string()).or_default();
+            if add {
+                *docs += 1;
+                for term in terms {
+                    *df.entry(term).or_insert(0) += 1;
+                }
+                continue;
+            }
+
+            *docs -= 1;
+            for term in terms {
+                if let Some(count) = df.get_mut(&term) {
+                    *count -= 1;
+                    if *count == 0 {
+                        df.remove(&term);
+                    }
+                }
+            }
+            if *docs == 0 {
+                self.field_docs.remove(path);
+                self.field_df.remove(path);
+            }
+        }
+    }
+
+    /// The document length of a token stream: its tokens, pruned ones
+    /// included, but not the `FIELD:` tokens of its keys.
+    fn content_length(&self, tokens: &[TermId]) -> u32 {
+        tokens
+            .iter()
+            .filter(|term| {
+                **term == PRUNED_TERM || !self.terms[**term as usize].starts_with(FIELD_KEY_PREFIX)
+            })
+            .count() as u32
+    }
+
+    fn intern(&mut self, token: &str) -> TermId {
+        if let Some(term) = self.term_id(token) {
+            return term;
+        }
+
+        let term = self.terms.len() as TermId;
+        self.terms.push(token.to_string());
+        self.term_ids.insert(token.to_string(), term);
+        term
+    }
+
+    fn term_id(&self, token: &str) -> Option<TermId> {
+        self.term_ids.get(token).copied()
+    }
+
+    fn unindex_document(&mut self, id: u32) {
+        self.indexed_hashes.remove(&id);
+        self.count_fields(id, false);
+        self.tokens.remove(&id);
+        self.values.remove(&id);
+        self.field_tf.remove(&id);
+        if let Some(len) = self.doc_len.remove(&id) {
+            self.total_len -= len as u64;
+        }
+
+        if let Some(tf) = self.tf.remove(&id) {
+            for token in tf.keys() {
+                if let Some(count) = self.df.get_mut(token) {
+                    *count -= 1;
+                    if *count == 0 {
+                        self.df.remove(token);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Indexes every entry in `entries`. When a document store is configured,
+    /// the indexed entries are then moved out of `entries` into the store.
+    /// Entries this already indexed and that haven't changed since are skipped,
+    /// so calling it again just to be safe costs a hash per entry. Changing
+    /// the analyzers makes the next call index everything again.
+    pub fn tokenize_entries(&mut self) -> Result<()> {
+        self.rebuild_positions();
+
+        let mut ids = Vec::new();
+        let mut analyzed = Vec::new();
+        for entry in &self.entries {
+            let hash = content_hash(entry);
+            match self.entry_id(entry) {
+                Some(id)
+                    if self.indexed_hashes.get(&id) == Some(&hash) && self.tf.contains_key(&id) =>
+                {
+                    ids.push(id);
+                }
+                _ => {
+                    let entry = self.analyze_entry(entry)?;
+                    ids.push(entry.id);
+                    analyzed.push((entry, hash));
+                }
+            }
+        }
+
+        let mut changed = !analyzed.is_empty();
+        for (analyzed, hash) in analyzed {
+            let id = analyzed.id;
+            self.index_document(analyzed);
+            self.indexed_hashes.insert(id, hash);
+        }
+        if let Some(max) = self.max_features {
+            changed |= self.prune_vocabulary(max);
+        }
+        if let Some(store) = &mut self.store {
+            for (id, entry) in ids.into_iter().zip(self.entries.drain(..)) {
+                store.put(id, entry);
+            }
+            self.positions.clear();
+        }
+        if changed {
+            self.invalidate();
+        }
+
+        Ok(())
+    }
+
+    /// Reads JSON Lines from `reader`, indexing each entry as soon as it is
+    /// read, so no separate `tokenize_entries` pass is needed. With a document
+    /// store configured, entries go straight into it. Blank lines are skipped.
+    /// Returns the number of entries indexed.
+    ///
+    /// IDF depends on the whole corpus, so scores only settle once the stream
+    /// has been read to the end. A line that fails to parse or index stops the
+    /// stream; entries before it stay indexed.
+    pub fn index_stream<R: Read>(&mut self, reader: R) -> Result<usize> {
+        let mut count = 0;
+        for (line, text) in BufReader::new(reader).lines().enumerate() {
+            let text = text.with_context(|| format!("Failed to read line {}", line + 1))?;
+            if text.trim().is_empty() {
+                continue;
+            }
+
+            let entry: serde_json::Value = serde_json::from_str(&text)
+                .with_context(|| format!("Failed to parse line {}", line + 1))?;
+            // string ids must be registered before the entry can be analyzed,
+            // and unregistered again if it can't be
+            let (last_id, string_ids) = (self.last_id, self.string_ids.len());
+            self.assign_id(&entry);
+            let analyzed = match self.analyze_entry(&entry) {
+                Ok(analyzed) => analyzed,
+                Err(e) => {
+                    if self.string_ids.len() > string_ids {
+                        if let Some(serde_json::Value::String(key)) =
+                            entry.get(&self.config.id_field)
+                        {
+                            self.string_ids.remove(key);
+                        }
+                        self.last_id = last_id;
+                    }
+                    return Err(e.context(format!("Failed to index line {}", line + 1)));
+                }
+            };
+            let id = analyzed.id;
+            self.index_document(analyzed);
+            self.indexed_hashes.insert(id, content_hash(&entry));
+            match &mut self.store {
+                Some(store) => store.put(id, entry),
+                None => self.add(entry),
+            }
+            count += 1;
+        }
+        self.invalidate();
+
+        Ok(count)
+    }
+
+    /// Replaces the analyzer. A saved index records built-in analyzers and
+    /// rebuilds them on load; a custom one has to be set again in
+    /// `load_index_with`. Documents already indexed keep their tokens until
+    /// `rebuild`, or until the next `tokenize_entries` for those still in
+    /// `entries`.
+    pub fn set_analyzer(&mut self, analyzer: Box<dyn Analyzer>) {
+        self.analyzer = analyzer;
+        self.indexed_hashes.clear();
+        self.invalidate();
+    }
+
+    /// Analyzes `field` and everything nested below it with `analyzer` instead
+    /// of the default one; the most specific registered field wins. Query
+    /// clauses scoped to the field (`code:AB-12`) use it too, while unscoped
+    /// clauses always go through the default analyzer. Saved and restored like
+    /// `set_analyzer`'s, and only affects documents indexed afterwards.
+    pub fn set_field_analyzer(&mut self, field: &str, analyzer: Box<dyn Analyzer>) {
+        self.field_analyzers.insert(field.to_string(), analyzer);
+        self.indexed_hashes.clear();
+        self.invalidate();
+    }
+
+    /// Indexes the text `derive` returns for each entry as a field called
+    /// `name`, e.g. a price bucket or a full name built from two fields, so it
+    /// can be searched without storing it in the entries. Query it like any
+    /// field (`price_range:cheap`); entries for which `derive` returns `None`
+    /// don't get the field. A derived field named like a real one adds to its
+    /// tokens. A saved index only records the field's name, so register it
+    /// again in `load_index_with`. Entries indexed before registering one
+    /// don't get it until they're reindexed.
+    pub fn add_derived_field<F>(&mut self, name: &str, derive: F)
+    where
+        F: Fn(&serde_json::Value) -> Option<String> + Send + Sync + 'static,
+    {
+        self.derived_fields.push(DerivedField {
+            name: name.to_string(),
+            derive: Box::new(derive),
+        });
+        self.indexed_hashes.clear();
+        self.invalidate();
+    }
+
+    fn analyzer_for(&self, path: &str) -> &dyn Analyzer {
+        self.field_analyzers
+            .iter()
+            .filter(|(field, _)| path_covers(field, path))
+            .max_by_key(|(field, _)| field.len())
+            .map_or(self.analyzer.as_ref(), |(_, analyzer)| analyzer.as_ref())
+    }
+
+    /// Keeps indexed documents in `store` instead of `entries`. Entries added
+    /// afterwards are staged in `entries` until `tokenize_entries` moves them.
+    pub fn set_store(&mut self, store: Box<dyn DocumentStore>) {
+        self.store = Some(store);
+    }
+
+    /// Writes the database to `writer`: a magic number and format version,
+    /// then the same snapshot `Serialize` produces. Analyzers are saved as
+    /// their `BuiltinAnalyzer`, or as custom, and derived fields by name only;
+    /// documents in a `DocumentStore` are not saved.
+    pub fn save_index<W: Write>(&self, mut writer: W) -> Result<()> {
+        writer
+            .write_all(INDEX_MAGIC)
+            .and_then(|_| writer.write_all(&INDEX_FORMAT_VERSION.to_le_bytes()))
+            .context("Failed to write index header")?;
+        serde_json::to_writer(writer, self).context("Failed to write index")
+    }
+
+    /// Reads a database written by `save_index`. Fails before reading the rest
+    /// if the header is missing or was written by another format version, and
+    /// after reading it if the index was built with custom analyzers or
+    /// derived fields, which only `load_index_with` can put back.
+    pub fn load_index<R: Read>(reader: R) -> Result<Self> {
+        Self::load_index_with(reader, |_| {})
+    }
+
+    /// Like `load_index`, but calls `setup` on the loaded database to register
+    /// its custom analyzers and derived fields again, with `set_analyzer`,
+    /// `set_field_analyzer` and `add_derived_field`. Built-in analyzers are
+    /// restored without it. Fails if the database then doesn't have the
+    /// analyzers and derived fields, by name, that it was saved with.
+    pub fn load_index_with<R, F>(mut reader: R, setup: F) -> Result<Self>
+    where
+        R: Read,
+        F: FnOnce(&mut Self),
+    {
+        let mut header = [0; 8];
+        reader
+            .read_exact(&mut header)
+            .context("Failed to read index header")?;
+        if &header[..4] != INDEX_MAGIC {
+            bail!(
+                "Not a saved index: the header does not start with {}",
+                String::from_utf8_lossy(INDEX_MAGIC)
+            );
+        }
+        let version = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+        if version != INDEX_FORMAT_VERSION {
+            bail!(
+                "Index has format version {}, this build reads version {}",
+                version,
+                INDEX_FORMAT_VERSION
+            );
+        }
+
+        let snapshot =
+            serde_json::from_reader(BufReader::new(reader)).context("Failed to read index")?;
+        Self::restore(snapshot, setup)
+    }
+
+    /// Turns a snapshot back into a database, rebuilding built-in analyzers
+    /// and letting `setup` put custom analyzers and derived fields back. Fails
+    /// unless the database then analyzes documents as the saved one did.
+    fn restore<F: FnOnce(&mut Self)>(snapshot: DatabaseSnapshot, setup: F) -> Result<Self> {
+        let build = |saved: Option<BuiltinAnalyzer>| match saved {
+            Some(analyzer) => analyzer.build(),
+            // a placeholder, `setup` has to replace it
+            None => Ok(Box::new(LexerAnalyzer) as Box<dyn Analyzer>),
+        };
+        let analyzer = build(snapshot.analyzer)?;
+        let field_analyzers = snapshot
+            .field_analyzers
+            .iter()
+            .map(|(field, saved)| Ok((field.to_string(), build(*saved)?)))
+            .collect::<Result<HashMap<String, Box<dyn Analyzer>>>>()?;
+        let saved_analyzer = snapshot.analyzer;
+        let saved_field_analyzers = snapshot.field_analyzers;
+        let saved_derived_fields = snapshot.derived_fields;
+
+        let mut db = Self {
+            entries: snapshot.entries,
+            positions: HashMap::new(),
+            string_ids: snapshot.string_ids,
+            last_id: snapshot.last_id,
+            tokens: snapshot.tokens,
+            values: snapshot.values,
+            term_ids: HashMap::new(),
+            terms: snapshot.terms,
+            tf: snapshot.tf,
+            doc_len: HashMap::new(),
+            total_len: 0,
+            df: snapshot.df,
+            field_tf: snapshot.field_tf,
+            field_docs: HashMap::new(),
+            field_df: HashMap::new(),
+            indexed_hashes: HashMap::new(),
+            analyzer,
+            field_analyzers,
+            derived_fields: Vec::new(),
+            store: None,
+            cached_results: HashMap::new(),
+            generation: 0,
+            cache_ttl: snapshot.cache_ttl,
+            tie_breakers: snapshot.tie_breakers,
+            tie_policy: snapshot.tie_policy,
+            demotions: snapshot.demotions,
+            config: snapshot.config,
+            synonyms: snapshot.synonyms,
+            stop_words: snapshot.stop_words,
+            synonym_weight: snapshot.synonym_weight,
+            score_mode: snapshot.score_mode,
+            idf_variant: snapshot.idf_variant,
+            log_base: snapshot.log_base,
+            default_operator: snapshot.default_operator,
+            empty_query: snapshot.empty_query,
+            max_results: snapshot.max_results,
+            max_features: snapshot.max_features,
+            length_normalization: snapshot.length_normalization,
+            idf_floor: snapshot.idf_floor,
+            max_query_length: snapshot.max_query_length,
+            max_query_tokens: snapshot.max_query_tokens,
+            per_field_idf: snapshot.per_field_idf,
+        };
+
+        db.term_ids = db
+            .terms
+            .iter()
+            .enumerate()
+            .map(|(term, token)| (token.to_string(), term as TermId))
+            .collect();
+        db.doc_len = db
+            .tokens
+            .iter()
+            .map(|(id, tokens)| (*id, db.content_length(tokens)))
+            .collect();
+        db.total_len = db.doc_len.values().map(|len| *len as u64).sum();
+        let ids = db.field_tf.keys().copied().collect::<Vec<u32>>();
+        for id in ids {
+            db.count_fields(id, true);
+        }
+        db.rebuild_positions();
+
+        setup(&mut db);
+        check_analyzer("analyzer", saved_analyzer, db.analyzer.builtin())?;
+        for (field, saved) in &saved_field_analyzers {
+            let current = db
+                .field_analyzers
+                .get(field)
+                .with_context(|| format!("The index was built with an analyzer for `{}`", field))?;
+            check_analyzer(
+                &format!("analyzer for `{}`", field),
+                *saved,
+                current.builtin(),
+            )?;
+        }
+        if let Some(field) = db
+            .field_analyzers
+            .keys()
+            .find(|field| !saved_field_analyzers.contains_key(*field))
+        {
+            bail!("The index was built without an analyzer for `{}`", field);
+        }
+        let derived_fields = db
+            .derived_fields
+            .iter()
+            .map(|field| field.name.to_string())
+            .collect::<Vec<String>>();
+        if derived_fields != saved_derived_fields {
+            bail!(
+                "The index was built with derived fields {:?}, but {:?} are registered",
+                saved_derived_fields,
+                derived_fields
+            );
+        }
+
+        Ok(db)
+    }
+
+    /// Discards every derived structure and re-indexes `entries` from scratch.
+    /// Use this after mutating `entries` directly, or whenever the incremental
+    /// bookkeeping is suspected to have drifted. Documents already moved to a
+    /// document store are read back from it and re-indexed too. String ids
+    /// keep the internal ids they were given, so ids handed out earlier stay
+    /// valid; keys no longer found in `entries` are forgotten.
+    pub fn rebuild(&mut self) -> Result<()> {
+        if let Some(store) = &mut self.store {
+            let mut ids = self.tf.keys().copied().collect::<Vec<u32>>();
+            ids.sort();
+            for id in ids {
+                if let Some(entry) = store.remove(id) {
+                    self.entries.push(entry);
+                }
+            }
+        }
+
+        let keys = self
+            .entries
+            .iter()
+            .filter_map(|entry| entry.get(&self.config.id_field)?.as_str())
+            .collect::<HashSet<&str>>();
+        self.string_ids.retain(|key, _| keys.contains(key.as_str()));
+        self.tokens.clear();
+        self.values.clear();
+        self.terms.clear();
+        self.term_ids.clear();
+        self.tf.clear();
+        self.doc_len.clear();
+        self.total_len = 0;
+        self.df.clear();
+        self.field_tf.clear();
+        self.field_docs.clear();
+        self.field_df.clear();
+        self.indexed_hashes.clear();
+        self.invalidate();
+
+        self.tokenize_entries()
+    }
+
+    /// Removes the entry with the given id along with everything indexed for it.
+    pub fn remove(&mut self, id: u32) -> Option<serde_json::Value> {
+        self.remove_ids(&HashSet::from([id]))
+            .pop()
+            .map(|(_, entry)| entry)
+    }
+
+    /// Removes the entries with the given ids, from `entries` or the store,
+    /// and everything indexed for them, in one pass over `entries`. Returns
+    /// what was removed, entries first, in their order.
+    fn remove_ids(&mut self, ids: &HashSet<u32>) -> Vec<(u32, serde_json::Value)> {
+        let mut removed = Vec::new();
+        let mut found = HashSet::new();
+        let mut entries = std::mem::take(&mut self.entries);
+        entries.retain(|entry| match self.entry_id(entry) {
+            // like `entry_position`, only the first entry with an id counts
+            Some(id) if ids.contains(&id) && found.insert(id) => {
+                removed.push((id, entry.clone()));
+                false
+            }
+            _ => true,
+        });
+        self.entries = entries;
+        if let Some(store) = &mut self.store {
+            for id in ids.iter().filter(|id| !found.contains(*id)) {
+                if let Some(entry) = store.remove(*id) {
+                    removed.push((*id, entry));
+                }
+            }
+        }
+        if removed.is_empty() {
+            return removed;
+        }
+
+        for (id, entry) in &removed {
+            if let Some(serde_json::Value::String(key)) = entry.get(&self.config.id_field) {
+                self.string_ids.remove(key);
+            }
+            self.unindex_document(*id);
+        }
+        self.rebuild_positions();
+        self.invalidate();
+
+        removed
+    }
+
+    /// Keeps only the entries for which `f` returns true, removing the rest
+    /// with `remove`. Covers entries moved to the document store as well.
+    /// Entries without a usable id can't be removed by id and are kept.
+    pub fn retain<F: Fn(&serde_json::Value) -> bool>(&mut self, f: F) {
+        self.remove_where(|entry| !f(entry));
+    }
+
+    /// Removes every entry for which `f` returns true, like the inverse of
+    /// `retain`, and returns the removed ids in ascending order, e.g. to
+    /// cascade the deletes elsewhere.
+    pub fn remove_where<F: Fn(&serde_json::Value) -> bool>(&mut self, f: F) -> Vec<u32> {
+        let ids = self.ids_where(f).into_iter().collect::<HashSet<u32>>();
+        let mut ids = self
+            .remove_ids(&ids)
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect::<Vec<u32>>();
+        ids.sort_unstable();
+
+        ids
+    }
+
+    /// Removes every entry whose `field`, a top-level key or dotted path,
+    /// equals `value`, e.g. to delete by a natural key before re-adding.
+    /// Values compare like `Filter::Eq`, so `1` matches `1.0`. Returns how many
+    /// entries were removed.
+    pub fn remove_by(&mut self, field: &str, value: &serde_json::Value) -> usize {
+        let filter = Filter::eq(field, value.clone());
+        self.remove_where(|entry| filter.matches(entry)).len()
+    }
+
+    /// Ids of the entries, in `entries` or the document store, for which `f`
+    /// returns true. Entries without a usable id are left out.
+    fn ids_where<F: Fn(&serde_json::Value) -> bool>(&self, f: F) -> Vec<u32> {
+        let mut ids = self
+            .entries
+            .iter()
+            .filter(|entry| f(entry))
+            .filter_map(|entry| self.entry_id(entry))
+            .collect::<Vec<u32>>();
+        if let Some(store) = &self.store {
+            for id in self.tf.keys() {
+                if self.entry_position(*id).is_some() {
+                    continue;
+                }
+                if store.get(*id).is_some_and(|entry| f(&entry)) {
+                    ids.push(*id);
+                }
+            }
+        }
+
+        ids
+    }
+
+    /// Replaces the entry whose `key_field` equals `entry`'s, or adds `entry`
+    /// if there is none, and returns its id. A replaced entry keeps its id
+    /// and is re-indexed if it was indexed, like with `update`; should several
+    /// entries share the key, the one with the lowest id is replaced and the
+    /// others removed. A new entry is indexed right away, taking the id in its
+    /// id field or, without one, the next free id.
+    pub fn upsert(&mut self, key_field: &str, mut entry: serde_json::Value) -> Result<u32> {
+        let key = get_path(&entry, key_field)
+            .with_context(|| format!("Failed to get key field `{}`", key_field))?;
+        let filter = Filter::eq(key_field, key.clone());
+        let mut ids = self.ids_where(|e| filter.matches(e));
+        ids.sort();
+
+        if let Some((&id, duplicates)) = ids.split_first() {
+            for duplicate in duplicates {
+                self.remove(*duplicate);
+            }
+            self.update(id, entry)?;
+            return Ok(id);
+        }
+
+        let object = entry
+            .as_object_mut()
+            .context("Entry must be a JSON object")?;
+        if !object.contains_key(&self.config.id_field) {
+            let id = self.next_id();
+            object.insert(self.config.id_field.clone(), serde_json::Value::from(id));
+        }
+        let id = self.assign_id(&entry).with_context(|| {
+            format!(
+                "Id field `{}` must be an unsigned integer or a string",
+                self.config.id_field
+            )
+        })?;
+        if self.get(id).is_some() {
+            bail!("Id {} is already used by an entry with another key", id);
+        }
+
+        let analyzed = self.analyze_entry(&entry)?;
+        self.index_document(analyzed);
+        self.indexed_hashes.insert(id, content_hash(&entry));
+        match &mut self.store {
+            Some(store) => store.put(id, entry),
+            None => {
+                self.positions.insert(id, self.entries.len());
+                self.entries.push(entry);
+            }
+        }
+        self.invalidate();
+
+        Ok(id)
+    }
+
+    /// Reclaims memory after many removals: drops vocabulary no indexed
+    /// document uses anymore, renumbering the remaining terms, rebuilds the
+    /// id to position map and shrinks every collection to fit.
+    pub fn compact(&mut self) {
+        let mut remap = HashMap::new();
+        let mut terms = Vec::new();
+        for (term, token) in std::mem::take(&mut self.terms).into_iter().enumerate() {
+            if self.df.contains_key(&(term as TermId)) {
+                remap.insert(term as TermId, terms.len() as TermId);
+                terms.push(token);
+            }
+        }
+        self.terms = terms;
+        self.term_ids = self
+            .terms
+            .iter()
+            .enumerate()
+            .map(|(term, token)| (token.to_string(), term as TermId))
+            .collect();
+
+        let renumber = |tf: &HashMap<TermId, u32>| {
+            tf.iter()
+                .map(|(term, count)| (remap[term], *count))
+                .collect::<HashMap<TermId, u32>>()
+        };
+        for tokens in self.tokens.values_mut() {
+            for term in tokens.iter_mut().filter(|term| **term != PRUNED_TERM) {
+                *term = remap[term];
+            }
+            tokens.shrink_to_fit();
+        }
+        for tf in self.tf.values_mut() {
+            *tf = renumber(tf);
+        }
+        for fields in self.field_tf.values_mut() {
+            for (_, tf) in fields.iter_mut() {
+                *tf = renumber(tf);
+            }
+            fields.shrink_to_fit();
+        }
+        self.df = renumber(&self.df);
+        for df in self.field_df.values_mut() {
+            *df = renumber(df);
+        }
+
+        self.rebuild_positions();
+        self.entries.shrink_to_fit();
+        self.positions.shrink_to_fit();
+        self.string_ids.shrink_to_fit();
+        self.tokens.shrink_to_fit();
+        self.terms.shrink_to_fit();
+        self.term_ids.shrink_to_fit();
+        self.tf.shrink_to_fit();
+        self.doc_len.shrink_to_fit();
+        self.field_tf.shrink_to_fit();
+        self.field_docs.shrink_to_fit();
+        self.field_df.shrink_to_fit();
+        self.indexed_hashes.shrink_to_fit();
+        self.cached_results.shrink_to_fit();
+    }
+
+    /// Replaces the entry with the given id, keeping its id field. If the entry
+    /// was already tokenized it is re-indexed right away.
+    pub fn update(&mut self, id: u32, mut entry: serde_json::Value) -> Result<()> {
+        let key = self
+            .get(id)
+            .with_context(|| format!("Failed to find entry {}", id))?
+            .get(&self.config.id_field)
+            .cloned()
+            .unwrap_or_else(|| serde_json::Value::from(id));
+        entry
+            .as_object_mut()
+            .context("Entry must be a JSON object")?
+            .insert(self.config.id_field.clone(), key);
+
+        if self.tf.contains_key(&id) {
+            let analyzed = self.analyze_entry(&entry)?;
+            self.index_document(analyzed);
+        }
+        match (self.entry_position(id), &mut self.store) {
+            (Some(position), _) => self.entries[position] = entry,
+            (None, Some(store)) => store.put(id, entry),
+            (None, None) => unreachable!("entry {} was found above", id),
+        }
+        self.invalidate();
+
+        Ok(())
+    }
+
+    fn get_tf(&self, key: &u32, token: &str) -> f32 {
+        let top = self
+            .term_id(token)
+            .and_then(|term| self.tf.get(key).unwrap().get(&term))
+            .unwrap_or(&0);
+        let bottom = self.normalized_length(key);
+        if bottom == 0.0 {
+            return 0.0;
+        }
+
+        *top as f32 / bottom
+    }
+
+    /// The length TF is divided by: the document's own length under full
+    /// normalization, the average length with none, and in between for
+    /// `length_normalization` values between 0.0 and 1.0. Zero for documents
+    /// without tokens.
+    fn normalized_length(&self, key: &u32) -> f32 {
+        let length = self.doc_len.get(key).copied().unwrap_or(0);
+        if length == 0 {
+            return 0.0;
+        }
+
+        let slope = self.length_normalization;
+        if slope == 1.0 {
+            return length as f32;
+        }
+        slope * length as f32 + (1.0 - slope) * self.average_document_length()
+    }
+
+    /// Like `get_term_tf`, but with each occurrence counted as the boost of
+    /// the field it is in.
+    fn get_boosted_tf(&self, key: &u32, term: &QueryTerm, boosts: &HashMap<String, f32>) -> f32 {
+        let top = self
+            .term_id(&term.token)
+            .zip(self.field_tf.get(key))
+            .map(|(id, fields)| {
+                fields
+                    .iter()
+                    .filter(|(field, _)| {
+                        term.field
+                            .as_ref()
+                            .is_none_or(|p| path_covers(p, &field.path))
+                    })
+                    .filter_map(|(field, tf)| {
+                        Some(*tf.get(&id)? as f32 * boost_for(boosts, &field.path))
+                    })
+                    .sum::<f32>()
+            })
+            .unwrap_or(0.0);
+        let bottom = self.normalized_length(key);
+        if bottom == 0.0 {
+            return 0.0;
+        }
+
+        top / bottom
+    }
+
+    /// Like `get_tf`, but only counts occurrences in fields matching `scope`.
+    fn get_scoped_tf<F>(&self, key: &u32, token: &str, scope: F) -> f32
+    where
+        F: Fn(&FieldKey) -> bool,
+    {
+        let top = self
+            .term_id(token)
+            .zip(self.field_tf.get(key))
+            .map(|(term, fields)| {
+                fields
+                    .iter()
+                    .filter(|(field, _)| scope(field))
+                    .filter_map(|(_, tf)| tf.get(&term))
+                    .sum::<u32>()
+            })
+            .unwrap_or(0);
+        let bottom = self.normalized_length(key);
+        if bottom == 0.0 {
+            return 0.0;
+        }
+
+        top as f32 / bottom
+    }
+
+    /// The document's tokens in the order they were indexed. Tokens removed
+    /// by `set_max_features` are left out.
+    pub fn tokens_for(&self, id: u32) -> Option<Vec<&str>> {
+        self.tokens.get(&id).map(|tokens| {
+            tokens
+                .iter()
+                .filter(|term| **term != PRUNED_TERM)
+                .map(|term| self.terms[*term as usize].as_str())
+                .collect()
+        })
+    }
+
+    pub fn document_length(&self, id: u32) -> Option<usize> {
+        self.doc_len.get(&id).map(|len| *len as usize)
+    }
+
+    pub fn average_document_length(&self) -> f32 {
+        if self.tf.is_empty() {
+            return 0.0;
+        }
+
+        self.total_len as f32 / self.tf.len() as f32
+    }
+
+    pub fn top_terms(&self, n: usize) -> Vec<(String, u32)> {
+        let mut totals: HashMap<TermId, u32> = HashMap::new();
+        for tf in self.tf.values() {
+            for (term, count) in tf {
+                *totals.entry(*term).or_insert(0) += count;
+            }
+        }
+
+        let mut terms = totals
+            .into_iter()
+            .map(|(term, count)| (self.terms[term as usize].to_string(), count))
+            .collect::<Vec<(String, u32)>>();
+        terms.sort_by(|(a_token, a), (b_token, b)| b.cmp(a).then_with(|| a_token.cmp(b_token)));
+        terms.truncate(n);
+
+        terms
+    }
+
+    /// Summary metrics over the indexed vocabulary, for monitoring index
+    /// quality over time. An empty index reports zeros.
+    pub fn vocab_stats(&self) -> VocabStats {
+        let mut frequencies = self.df.values().copied().collect::<Vec<u32>>();
+        frequencies.sort_unstable();
+
+        let n = frequencies.len();
+        let median = match n {
+            0 => 0.0,
+            _ if n % 2 == 0 => (frequencies[n / 2 - 1] + frequencies[n / 2]) as f32 / 2.0,
+            _ => frequencies[n / 2] as f32,
+        };
+        let mean = if n == 0 {
+            0.0
+        } else {
+            frequencies.iter().map(|df| *df as f64).sum::<f64>() as f32 / n as f32
+        };
+
+        VocabStats {
+            unique_tokens: n,
+            mean_document_frequency: mean,
+            median_document_frequency: median,
+            hapax_tokens: frequencies.iter().filter(|df| **df == 1).count(),
+        }
+    }
+
+    /// A rough estimate of the memory the database holds, in bytes, for
+    /// deciding when to shard or prune: entries, the index, the result cache
+    /// and the synonym and stop word dictionaries, from their element counts,
+    /// capacities and string lengths. Allocator overhead isn't counted, nor
+    /// is whatever an analyzer or a `DocumentStore` holds.
+    pub fn estimated_memory_bytes(&self) -> usize {
+        let mut bytes = std::mem::size_of::<Self>();
+        bytes += vec_bytes(&self.entries, self.entries.capacity());
+        bytes += self.entries.iter().map(json_heap_bytes).sum::<usize>();
+        bytes += map_bytes(&self.positions);
+        bytes += map_bytes(&self.string_ids)
+            + self.string_ids.keys().map(String::capacity).sum::<usize>();
+        bytes += map_bytes(&self.tokens);
+        bytes += self
+            .tokens
+            .values()
+            .map(|tokens| vec_bytes(tokens, tokens.capacity()))
+            .sum::<usize>();
+        bytes += map_bytes(&self.values);
+        bytes += self
+            .values
+            .values()
+            .map(|values| vec_bytes(values, values.capacity()))
+            .sum::<usize>();
+        bytes += self.terms.capacity() * std::mem::size_of::<String>();
+        bytes += self.terms.iter().map(String::capacity).sum::<usize>();
+        bytes +=
+            map_bytes(&self.term_ids) + self.term_ids.keys().map(String::capacity).sum::<usize>();
+        bytes += map_bytes(&self.tf) + self.tf.values().map(map_bytes).sum::<usize>();
+        bytes += map_bytes(&self.doc_len) + map_bytes(&self.df) + map_bytes(&self.indexed_hashes);
+        bytes += map_bytes(&self.field_tf);
+        for fields in self.field_tf.values() {
+            bytes += vec_bytes(fields, fields.capacity());
+            bytes += fields
+                .iter()
+                .map(|(field, tf)| field.path.capacity() + map_bytes(tf))
+                .sum::<usize>();
+        }
+        bytes += map_bytes(&self.field_docs)
+            + self.field_docs.keys().map(String::capacity).sum::<usize>();
+        bytes += map_bytes(&self.field_df);
+        bytes += self
+            .field_df
+            .iter()
+            .map(|(path, df)| path.capacity() + map_bytes(df))
+            .sum::<usize>();
+        bytes += map_bytes(&self.cached_results);
+        for (query, cached) in &self.cached_results {
+            bytes += query.capacity() + vec_bytes(&cached.result, cached.result.capacity());
+            bytes += cached
+                .result
+                .iter()
+                .map(|(entry, _)| json_heap_bytes(entry))
+                .sum::<usize>();
+        }
+        bytes += map_bytes(&self.synonyms);
+        for (word, alternatives) in &self.synonyms {
+            bytes += word.capacity() + vec_bytes(alternatives, alternatives.capacity());
+            bytes += alternatives.iter().map(String::capacity).sum::<usize>();
+        }
+        bytes += self.stop_words.capacity() * (std::mem::size_of::<String>() + 1);
+        bytes += self.stop_words.iter().map(String::capacity).sum::<usize>();
+
+        bytes
+    }
+
+    /// The `n` tokens contributing the most TF-IDF to the document, best first,
+    /// e.g. for tagging. Tokens found in every document score 0.0 and are left
+    /// out. Unknown ids have no keywords.
+    pub fn keywords(&self, id: u32, n: usize) -> Vec<(String, f32)> {
+        let Some(tf) = self.tf.get(&id) else {
+            return Vec::new();
+        };
+
+        let mut keywords = tf
+            .keys()
+            .map(|term| {
+                let token = &self.terms[*term as usize];
+                (
+                    token.to_string(),
+                    self.get_tf(&id, token) * self.get_idf(token),
+                )
+            })
+            .filter(|(_, score)| *score > 0.0)
+            .collect::<Vec<(String, f32)>>();
+        keywords.sort_by(|(a_token, a), (b_token, b)| {
+            b.total_cmp(a).then_with(|| a_token.cmp(b_token))
+        });
+        keywords.truncate(n);
+
+        keywords
+    }
+
+    /// The configured `IdfVariant`, or 0.0 for a token no document contains so
+    /// out-of-vocabulary query terms never carry a phantom weight.
+    fn get_idf(&self, token: &str) -> f32 {
+        let df = self.document_frequency(token);
+        if df == 0 {
+            return 0.0;
+        }
+
+        self.idf_from(self.document_count() as f32, df as f32)
+    }
+
+    /// IDF computed within one field: `n` is the number of documents with a
+    /// `path` field and `df` the number of those containing the token there.
+    fn field_idf(&self, path: &str, term: TermId) -> f32 {
+        let n = self.field_docs.get(path).copied().unwrap_or(0);
+        let df = self
+            .field_df
+            .get(path)
+            .and_then(|df| df.get(&term))
+            .copied()
+            .unwrap_or(0);
+        if df == 0 {
+            return 0.0;
+        }
+
+        self.idf_from(n as f32, df as f32)
+    }
+
+    /// This database's IDF formula, floor included, for a token found in `df`
+    /// of `n` documents.
+    pub(crate) fn idf_from(&self, n: f32, df: f32) -> f32 {
+        let idf = match self.idf_variant {
+            IdfVariant::Standard => self.log_base.log(n / df),
+            IdfVariant::Smoothed => self.log_base.log(1.0 + n / df),
+            IdfVariant::Probabilistic => self.log_base.log((n - df) / df).max(0.0),
+        };
+
+        idf.max(self.idf_floor)
+    }
+
+    /// The IDF of every query token, computed once per query so scoring a
+    /// document only has to look them up.
+    fn query_idf(&self, terms: &[QueryTerm]) -> HashMap<String, f32> {
+        terms
+            .iter()
+            .map(|t| (t.token.to_string(), self.get_idf(&t.token)))
+            .collect()
+    }
+
+    fn calculate_tf_idf(&self, key: &u32, term: &QueryTerm, idf: &HashMap<String, f32>) -> f32 {
+        let tf = self.get_term_tf(key, term);
+        let idf = idf.get(&term.token).copied().unwrap_or(0.0);
+
+        tf * idf
+    }
+
+    fn entry_position(&self, id: u32) -> Option<usize> {
+        let has_id = |e: &serde_json::Value| self.entry_id(e) == Some(id);
+
+        // `entries` is public, so fall back to a scan if the map has drifted
+        match self.positions.get(&id) {
+            Some(&position) if self.entries.get(position).is_some_and(has_id) => Some(position),
+            _ => self.entries.iter().position(has_id),
+        }
+    }
+
+    /// Resolves an entry's id field to its internal id. Integer ids are used
+    /// as-is; string ids (e.g. UUIDs) map to internal ids assigned in insertion
+    /// order. Assigned ids, these and `add_typed`'s, come from one counter that
+    /// also moves past every integer id seen, so the same input in the same
+    /// order always gets the same ids and none is handed out twice. An integer
+    /// id added after a string one can still collide with it, so a database
+    /// should stick to one id type.
+    fn entry_id(&self, entry: &serde_json::Value) -> Option<u32> {
+        match entry.get(&self.config.id_field)? {
+            serde_json::Value::String(key) => self.id_for_key(key),
+            value => value.as_u64().map(|id| id as u32),
+        }
+    }
+
+    fn assign_id(&mut self, entry: &serde_json::Value) -> Option<u32> {
+        match entry.get(&self.config.id_field)? {
+            serde_json::Value::String(key) => {
+                if let Some(id) = self.id_for_key(key) {
+                    return Some(id);
+                }
+
+                let id = self.next_id();
+                self.string_ids.insert(key.to_string(), id);
+                Some(id)
+            }
+            value => {
+                let id = value.as_u64()? as u32;
+                self.last_id = self.last_id.max(id);
+                Some(id)
+            }
+        }
+    }
+
+    fn next_id(&mut self) -> u32 {
+        self.last_id += 1;
+        self.last_id
+    }
+
+    fn rebuild_positions(&mut self) {
+        let entries = std::mem::take(&mut self.entries);
+        self.positions = entries
+            .iter()
+            .enumerate()
+            .filter_map(|(position, e)| Some((self.assign_id(e)?, position)))
+            .collect();
+        self.entries = entries;
+    }
+
+    /// Returns every entry whose `field` (a top-level key or dotted path) is
+    /// exactly equal to `value`: those held in `entries` in insertion order,
+    /// then those in a document store by id. This bypasses tokenization and
+    /// ranking entirely.
+    pub fn find_by(
+        &self,
+        field: &str,
+        value: &serde_json::Value,
+    ) -> Vec<Cow<'_, serde_json::Value>> {
+        let mut found = self
+            .entries
+            .iter()
+            .filter(|entry| get_path(entry, field) == Some(value))
+            .map(Cow::Borrowed)
+            .collect::<Vec<_>>();
+        if self.store.is_some() {
+            let mut ids = self
+                .tf
+                .keys()
+                .filter(|id| self.entry_position(**id).is_none())
+                .collect::<Vec<_>>();
+            ids.sort_unstable();
+            found.extend(
+                ids.into_iter()
+                    .filter_map(|id| self.get(*id))
+                    .filter(|entry| get_path(entry, field) == Some(value)),
+            );
+        }
+
+        found
+    }
+
+    pub fn get(&self, id: u32) -> Option<Cow<'_, serde_json::Value>> {
+        match self.entry_position(id) {
+            Some(position) => Some(Cow::Borrowed(&self.entries[position])),
+            None => {
+                let store = self.store.as_ref()?;
+                match store.get_ref(id) {
+                    Some(entry) => Some(Cow::Borrowed(entry)),
+                    None => store.get(id).map(Cow::Owned),
+                }
+            }
+        }
+    }
+
+    pub(crate) fn document_count(&self) -> usize {
+        self.tf.len()
+    }
+
+    /// The single normalization path shared by indexing and querying, so both
+    /// sides always agree on token shape. With the default analyzer, subword
+    /// markers such as WordPiece's `##` are punctuation to the lexer and get
+    /// stripped, so a pre-split "##ie" is indexed and queried as the
+    /// standalone piece `IE`.
+    fn analyze_text(&self, analyzer: &dyn Analyzer, text: &str) -> Vec<String> {
+        let mut tokens = self.normalize(analyzer, text);
+        self.drop_stop_words(&mut tokens);
+
+        tokens
+    }
+
+    /// `analyze_text` without dropping stop words.
+    fn normalize(&self, analyzer: &dyn Analyzer, text: &str) -> Vec<String> {
+        let mut tokens = if self.config.fold_diacritics {
+            analyzer.analyze(&fold_diacritics(text))
+        } else {
+            analyzer.analyze(text)
+        };
+        self.normalize_numbers(&mut tokens);
+
+        tokens
+    }
+
+    fn normalize_numbers(&self, tokens: &mut [String]) {
+        if !self.config.normalize_numbers {
+            return;
+        }
+
+        for token in tokens {
+            if let Some(number) = canonical_number(token) {
+                *token = number;
+            }
+        }
+    }
+
+    fn drop_stop_words(&self, tokens: &mut Vec<String>) {
+        if !self.stop_words.is_empty() {
+            tokens.retain(|token| !self.stop_words.contains(token));
+        }
+    }
+
+    /// Adds the stop words in the file at `path`, one per line; blank lines
+    /// are skipped. Stop words are normalized like any text, so "The" and
+    /// "THE" are the same word, and then left out of both documents and
+    /// queries. Documents already indexed keep them until `rebuild`, or until
+    /// the next `tokenize_entries` for those still in `entries`.
+    pub fn load_stop_words<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read stop words from {}", path.display()))?;
+
+        for line in text.lines().filter(|line| !line.trim().is_empty()) {
+            let tokens = self.normalize(self.analyzer.as_ref(), line);
+            self.stop_words.extend(tokens);
+        }
+        self.indexed_hashes.clear();
+        self.invalidate();
+
+        Ok(())
+    }
+
+    /// Returns the tokens `text` becomes under this database's analysis, the
+    /// same pipeline documents and query clauses go through. Handy for
+    /// previewing a query or checking why something did not match.
+    pub fn tokenize(&self, text: &str) -> Vec<String> {
+        self.analyze_text(self.analyzer.as_ref(), text)
+    }
+
+    /// Like `tokenize`, but with the analyzer used for `field`.
+    pub fn tokenize_field(&self, field: &str, text: &str) -> Vec<String> {
+        self.analyze_text(self.analyzer_for(field), text)
+    }
+
+    /// Sets query-time synonyms: a query token found in `synonyms` also matches
+    /// each of its synonyms, scored at `weight` times a direct match. Keys and
+    /// values go through the same lexer as queries. The index is unaffected.
+    pub fn set_synonyms(&mut self, synonyms: HashMap<String, Vec<String>>, weight: f32) {
+        self.synonyms = synonyms
+            .iter()
+            .flat_map(|(word, alternatives)| {
+                let alternatives = alternatives
+                    .iter()
+                    .flat_map(|alternative| self.tokenize(alternative))
+                    .collect::<Vec<String>>();
+                self.tokenize(word)
+                    .into_iter()
+                    .map(move |token| (token, alternatives.clone()))
+            })
+            .collect();
+        self.synonym_weight = weight;
+        self.invalidate();
+    }
+
+    /// Parses `query` (see `query::parse` for the syntax) into the terms the
+    /// user typed, without synonyms.
+    fn parse_terms(&self, query: &str) -> Vec<QueryTerm> {
+        let mut terms = Vec::new();
+        let mut group = 0;
+        for (i, clause) in query::parse(query).into_iter().enumerate() {
+            let alternates = clause.alternatives.len() > 1;
+            for text in &clause.alternatives {
+                if self.config.index_field_keys && clause.field.as_deref() == Some("field") {
+                    terms.push(QueryTerm {
+                        token: field_key_token(text),
+                        weight: 1.0,
+                        field: None,
+                        group,
+                        phrase: None,
+                    });
+                    group += usize::from(!alternates);
+                    continue;
+                }
+
+                let tokens = match &clause.field {
+                    Some(field) => self.tokenize_field(field, text),
+                    None => self.tokenize(text),
+                };
+                let phrase = (clause.quoted && !alternates && tokens.len() > 1).then_some(i);
+                for token in tokens {
+                    terms.push(QueryTerm {
+                        token,
+                        weight: 1.0,
+                        field: clause.field.clone(),
+                        group,
+                        phrase,
+                    });
+                    group += usize::from(!alternates);
+                }
+            }
+            group += usize::from(alternates);
+        }
+
+        terms
+    }
+
+    /// `parse_terms` plus synonyms, after checking the query against the
+    /// configured limits.
+    fn query_terms(&self, query: &str) -> Result<Vec<QueryTerm>> {
+        if query.len() > self.max_query_length {
+            bail!(
+                "Query is {} bytes long, the limit is {}",
+                query.len(),
+                self.max_query_length
+            );
+        }
+
+        let terms = self.parse_terms(query);
+        if terms.len() > self.max_query_tokens {
+            bail!(
+                "Query has {} tokens, the limit is {}",
+                terms.len(),
+                self.max_query_tokens
+            );
+        }
+
+        Ok(self.expand_synonyms(terms))
+    }
+
+    /// Adds the synonyms of `terms`, which keep the field scope of the term
+    /// they expand.
+    fn expand_synonyms(&self, mut terms: Vec<QueryTerm>) -> Vec<QueryTerm> {
+        let mut expanded = Vec::new();
+        for term in &terms {
+            for synonym in self.synonyms.get(&term.token).into_iter().flatten() {
+                let known = terms
+                    .iter()
+                    .chain(&expanded)
+                    .any(|t| &t.token == synonym && t.field == term.field);
+                if !known {
+                    expanded.push(QueryTerm {
+                        token: synonym.to_string(),
+                        weight: self.synonym_weight,
+                        field: term.field.clone(),
+                        group: term.group,
+                        phrase: None,
+                    });
+                }
+            }
+        }
+        terms.append(&mut expanded);
+
+        terms
+    }
+
+    fn get_term_tf(&self, key: &u32, term: &QueryTerm) -> f32 {
+        match &term.field {
+            Some(field) => self.get_scoped_tf(key, &term.token, |f| path_covers(field, &f.path)),
+            None => self.get_tf(key, &term.token),
+        }
+    }
+
+    /// Scores documents against an already tokenized query, skipping query
+    /// parsing and analysis. Tokens must come from `self.tokenize`, which
+    /// gives them the index's normalized form whatever the analyzer; others
+    /// may not match.
+    pub fn search_tokens(&self, tokens: &[String]) -> Result<Vec<(u32, f32)>> {
+        self.score_tokens(&token_terms(tokens), None)
+    }
+
+    /// Like `search`, but gives up once `deadline` passes. A timed-out search
+    /// returns an error rather than partial results, since documents are not
+    /// scored in any meaningful order and a partial ranking would be misleading.
+    pub fn search_until(&self, query: &str, deadline: Instant) -> Result<Vec<(u32, f32)>> {
+        if query.trim().is_empty() {
+            return self.rank_empty_query(None);
+        }
+
+        let terms = self.query_terms(query)?;
+        self.score_tokens(&terms, Some(deadline))
+    }
+
+    /// Like `search_until` without a deadline, with the options carried by
+    /// `query`. Field boosts weigh each occurrence of a query term by the
+    /// boost of the field it is in, so a boost of 1.0 scores like no boost.
+    pub fn search_query(&self, query: &Query) -> Result<Vec<(u32, f32)>> {
+        if query.text.trim().is_empty() {
+            return self.rank_empty_query(query.filter.as_ref());
+        }
+
+        let terms = self.query_terms(&query.text)?;
+        let idf = self.query_idf(&terms);
+        let score = |id: &u32| self.score_boosted(id, &terms, &idf, &query.field_boosts);
+        // either way the filter runs before ranking truncates to
+        // `max_results`, so both orders keep the same documents
+        let Some(filter) = &query.filter else {
+            return self.rank_ids(self.tf.keys(), None, score);
+        };
+        match query.filter_order {
+            FilterOrder::BeforeScoring => self.rank_ids(self.tf.keys(), None, |id| {
+                let passes = self.matches(id, &terms)
+                    && self.get(*id).is_some_and(|entry| filter.matches(&entry));
+                if passes {
+                    score(id)
+                } else {
+                    0.0
+                }
+            }),
+            FilterOrder::AfterScoring => {
+                self.rank_with(self.tf.keys(), None, score, |_, entry, score| {
+                    filter.matches(entry).then_some(score)
+                })
+            }
+        }
+    }
+
+    /// Ranks each of `queries` and keeps its top `k`, e.g. to evaluate a query
+    /// set offline. IDF is looked up once per distinct token across the whole
+    /// batch, and the result cache is bypassed. Results come back in query
+    /// order, each failing on its own, e.g. for exceeding the query limits.
+    pub fn search_many(&self, queries: &[&str], k: usize) -> Vec<Result<Vec<(u32, f32)>>> {
+        let mut idf = HashMap::new();
+        queries
+            .iter()
+            .map(|query| {
+                if query.trim().is_empty() {
+                    let mut result = self.rank_empty_query(None)?;
+                    result.truncate(k);
+                    return Ok(result);
+                }
+
+                let terms = self.query_terms(query)?;
+                for term in &terms {
+                    idf.entry(term.token.clone())
+                        .or_insert_with(|| self.get_idf(&term.token));
+                }
+
+                let mut result = self.rank(None, |id| self.score_document(id, &terms, &idf))?;
+                result.truncate(k);
+                Ok(result)
+            })
+            .collect()
+    }
+
+    /// Starts a search-as-you-type session, see `IncrementalSearch`.
+    pub fn incremental_search(&self) -> IncrementalSearch<'_> {
+        IncrementalSearch {
+            db: self,
+            query: String::new(),
+            base: Vec::new(),
+            candidates: Vec::new(),
+        }
+    }
+
+    /// Ranks indexed documents by the cosine similarity of their TF-IDF
+    /// vectors to that of `doc`, analyzed like any document but not added,
+    /// and keeps the top `k`. Meant for spotting near-duplicates at ingest
+    /// time: a copy of an indexed document scores 1.0, or less if only its id
+    /// differs, since ids are indexed like other fields. Score mode,
+    /// length normalization, demotions and tie breakers don't apply; equal
+    /// scores are ordered by ascending id.
+    pub fn search_document(&self, doc: &serde_json::Value, k: usize) -> Result<Vec<(u32, f32)>> {
+        self.ensure_indexed()?;
+
+        let n = self.document_count() as f32;
+        let idf = |term: &TermId| {
+            self.df
+                .get(term)
+                .map_or(0.0, |df| self.idf_from(n, *df as f32))
+        };
+        // sum in term order so scores are identical on every run
+        let vector = |tf: &HashMap<TermId, u32>| {
+            let mut vector = tf
+                .iter()
+                .map(|(term, count)| (*term, *count as f32 * idf(term)))
+                .collect::<Vec<(TermId, f32)>>();
+            vector.sort_by_key(|(term, _)| *term);
+            let norm = vector.iter().map(|(_, w)| w * w).sum::<f32>().sqrt();
+            (vector, norm)
+        };
+
+        // tokens the index has never seen have no IDF and can't contribute
+        let tokens = self.analyze_fields(doc)?.tokens;
+        let mut tf = HashMap::new();
+        for term in tokens.iter().filter_map(|token| self.term_id(token)) {
+            *tf.entry(term).or_insert(0) += 1;
+        }
+        let (query, query_norm) = vector(&tf);
+        let query = query.into_iter().collect::<HashMap<TermId, f32>>();
+        if query_norm == 0.0 {
+            return Ok(Vec::new());
+        }
+
+        let mut result = Vec::new();
+        for (id, tf) in &self.tf {
+            let (document, norm) = vector(tf);
+            let dot = document
+                .iter()
+                .filter_map(|(term, w)| query.get(term).map(|q| q * w))
+                .sum::<f32>();
+            if dot > 0.0 {
+                result.push((*id, dot / (query_norm * norm)));
+            }
+        }
+        result.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        result.truncate(k);
+
+        Ok(result)
+    }
+
+    /// Scores only occurrences that come from JSON values of the given kind,
+    /// e.g. so a query for "2008" can target a numeric year rather than a title
+    /// containing the same digits. Document frequencies stay corpus-wide.
+    pub fn search_kind(&self, query: &str, kind: ValueKind) -> Result<Vec<(u32, f32)>> {
+        let terms = self.query_terms(query)?;
+        let idf = self.query_idf(&terms);
+        self.rank(None, |id| {
+            if !self.is_candidate(id, &terms) {
+                return 0.0;
+            }
+
+            terms
+                .iter()
+                .map(|t| {
+                    let tf = self.get_scoped_tf(id, &t.token, |f| {
+                        f.kind == kind && t.field.as_ref().is_none_or(|p| path_covers(p, &f.path))
+                    });
+                    tf * idf.get(&t.token).copied().unwrap_or(0.0) * t.weight
+                })
+                .sum::<f32>()
+        })
+    }
+
+    /// Like `search`, but only scores documents whose configured recency field
+    /// is at or after `since`. Documents without a numeric recency value are
+    /// skipped.
+    pub fn search_recent(&self, query: &str, since: f64) -> Result<Vec<(u32, f32)>> {
+        let field = self
+            .config
+            .recency_field
+            .as_deref()
+            .context("No recency field configured, see IndexConfig::recency_field")?;
+        let terms = self.query_terms(query)?;
+        let idf = self.query_idf(&terms);
+
+        // only documents matching the query are fetched to check their date
+        self.rank(None, |id| {
+            if !self.matches(id, &terms) {
+                return 0.0;
+            }
+            let recent = self
+                .get(*id)
+                .and_then(|entry| get_path(&entry, field).and_then(|value| value.as_f64()))
+                .is_some_and(|value| value >= since);
+            if !recent {
+                return 0.0;
+            }
+
+            self.score_document(id, &terms, &idf)
+        })
+    }
+
+    /// Reports how `query` is normalized, which tokens are known to the index
+    /// and which were dropped, and how many documents are candidates for it.
+    pub fn diagnose(&self, query: &str) -> QueryDiagnostics {
+        let mut dropped = Vec::new();
+        for clause in query::parse(query) {
+            let is_field_key =
+                self.config.index_field_keys && clause.field.as_deref() == Some("field");
+            if is_field_key {
+                continue;
+            }
+
+            let analyzer = match &clause.field {
+                Some(field) => self.analyzer_for(field),
+                None => self.analyzer.as_ref(),
+            };
+            for text in clause.alternatives {
+                let tokens = self.normalize(analyzer, &text);
+                if tokens.is_empty() {
+                    dropped.push(DroppedTerm {
+                        text,
+                        reason: DropReason::NoTokens,
+                    });
+                    continue;
+                }
+
+                for token in tokens {
+                    if self.stop_words.contains(&token) {
+                        dropped.push(DroppedTerm {
+                            text: token,
+                            reason: DropReason::StopWord,
+                        });
+                    }
+                }
+            }
+        }
+
+        let tokens = self
+            .parse_terms(query)
+            .into_iter()
+            .map(|t| t.token)
+            .collect::<Vec<String>>();
+        let mut in_vocabulary = Vec::new();
+        for token in &tokens {
+            if self.document_frequency(token) > 0 {
+                in_vocabulary.push(token.to_string());
+            } else {
+                dropped.push(DroppedTerm {
+                    text: token.to_string(),
+                    reason: DropReason::OutOfVocabulary,
+                });
+            }
+        }
+
+        let terms = self.expand_synonyms(self.parse_terms(query));
+        QueryDiagnostics {
+            tokens,
+            in_vocabulary,
+            dropped,
+            candidate_count: self.count_candidates(&terms),
+        }
+    }
+
+    /// Counts the documents sharing at least one token with `query`, synonyms
+    /// included, without scoring or fetching any of them, e.g. to ask the user
+    /// to refine a query that matches too much. The default operator and
+    /// phrases are ignored, so this is an upper bound on what `search`
+    /// returns. A single unscoped term is answered from its document
+    /// frequency; otherwise, as the index keeps no posting lists, each
+    /// document's term counts are checked.
+    pub fn candidate_count(&self, query: &str) -> Result<usize> {
+        let terms = self.query_terms(query)?;
+        Ok(self.count_candidates(&terms))
+    }
+
+    fn count_candidates(&self, terms: &[QueryTerm]) -> usize {
+        match terms {
+            [] => 0,
+            [term] if term.field.is_none() => self.document_frequency(&term.token) as usize,
+            _ => self
+                .tf
+                .keys()
+                .filter(|id| terms.iter().any(|t| self.get_term_tf(id, t) > 0.0))
+                .count(),
+        }
+    }
+
+    /// Whether the document is a candidate and contains at least one of the
+    /// terms, going by the index alone.
+    fn matches(&self, id: &u32, terms: &[QueryTerm]) -> bool {
+        self.is_candidate(id, terms) && terms.iter().any(|t| self.get_term_tf(id, t) > 0.0)
+    }
+
+    /// Explains the score document `id` gets for `query`, term by term. The
+    /// total matches what `search` would report under `ScoreMode::Sum`.
+    pub fn search_explain(&self, query: &str, id: u32) -> Result<Explanation> {
+        if !self.tf.contains_key(&id) {
+            bail!("Document {} is not indexed", id);
+        }
+
+        let terms = self
+            .query_terms(query)?
+            .into_iter()
+            .map(|term| {
+                let tf = self.get_term_tf(&id, &term);
+                let idf = self.get_idf(&term.token);
+                TermExplanation {
+                    score: tf * idf * term.weight,
+                    token: term.token,
+                    field: term.field,
+                    tf,
+                    idf,
+                    weight: term.weight,
+                }
+            })
+            .collect::<Vec<TermExplanation>>();
+
+        Ok(Explanation {
+            id,
+            score: terms.iter().map(|t| t.score).sum(),
+            terms,
+        })
+    }
+
+    pub(crate) fn document_frequency(&self, token: &str) -> u32 {
+        self.term_id(token)
+            .and_then(|term| self.df.get(&term))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Scores with caller-supplied IDF values instead of this database's own,
+    /// for when the corpus spans several databases.
+    pub(crate) fn search_tokens_with_idf(
+        &self,
+        tokens: &[String],
+        idf: &HashMap<String, f32>,
+    ) -> Result<Vec<(u32, f32)>> {
+        let terms = token_terms(tokens);
+        self.rank(None, |id| {
+            if !self.is_candidate(id, &terms) {
+                return 0.0;
+            }
+
+            tokens
+                .iter()
+                .map(|t| self.get_tf(id, t) * idf.get(t).copied().unwrap_or(0.0))
+                .sum::<f32>()
+        })
+    }
+
+    fn score_tokens(
+        &self,
+        terms: &[QueryTerm],
+        deadline: Option<Instant>,
+    ) -> Result<Vec<(u32, f32)>> {
+        let idf = self.query_idf(terms);
+        self.rank(deadline, |id| self.score_document(id, terms, &idf))
+    }
+
+    fn score_document(&self, id: &u32, terms: &[QueryTerm], idf: &HashMap<String, f32>) -> f32 {
+        if !self.is_candidate(id, terms) {
+            return 0.0;
+        }
+
+        match self.score_mode {
+            ScoreMode::Sum => terms
+                .iter()
+                .map(|t| self.calculate_tf_idf(id, t, idf) * t.weight)
+                .sum::<f32>(),
+            ScoreMode::BestField => self
+                .field_scores(id, terms, idf, &HashMap::new())
+                .fold(0.0, f32::max),
+            ScoreMode::DisMax { tie_breaker } => {
+                let (best, total) = self
+                    .field_scores(id, terms, idf, &HashMap::new())
+                    .fold((0.0, 0.0), |(best, total), score| {
+                        (f32::max(best, score), total + score)
+                    });
+                best + tie_breaker * (total - best)
+            }
+            ScoreMode::Overlap => self.matched_terms(id, terms).map(|t| t.weight).sum::<f32>(),
+            ScoreMode::Jaccard => {
+                let shared = self.matched_terms(id, terms).count();
+                let mut query = terms.iter().map(|t| &t.token).collect::<Vec<_>>();
+                query.sort();
+                query.dedup();
+                let document = self.tf.get(id).map_or(0, |tf| tf.len());
+
+                let union = query.len() + document - shared;
+                if union == 0 {
+                    return 0.0;
+                }
+                shared as f32 / union as f32
+            }
+            ScoreMode::Coverage => {
+                let length = self.doc_len.get(id).copied().unwrap_or(0);
+                if length == 0 {
+                    return 0.0;
+                }
+
+                let covered = self
+                    .matched_terms(id, terms)
+                    .filter_map(|t| self.tf.get(id)?.get(&self.term_id(&t.token)?))
+                    .sum::<u32>();
+                covered as f32 / length as f32
+            }
+        }
+    }
+
+    /// The query terms found in the document, counting each token once even if
+    /// several clauses or synonyms produced it.
+    fn matched_terms<'a>(
+        &'a self,
+        id: &'a u32,
+        terms: &'a [QueryTerm],
+    ) -> impl Iterator<Item = &'a QueryTerm> + 'a {
+        terms.iter().enumerate().filter_map(move |(i, term)| {
+            let first = !terms[..i].iter().any(|t| t.token == term.token);
+            let matched = terms[i..]
+                .iter()
+                .filter(|t| t.token == term.token)
+                .find(|t| self.get_term_tf(id, t) > 0.0);
+            matched.filter(|_| first)
+        })
+    }
+
+    /// Like `score_document`, but with every field's contribution multiplied
+    /// by its boost. `Sum` keeps its formula, TF relative to the whole
+    /// document, and weighs each occurrence by the boost of the field it is
+    /// in, so boosting every field by 1.0 changes nothing. `BestField` and
+    /// `DisMax` multiply each field's score by its boost. `Overlap`, `Jaccard`
+    /// and `Coverage` ignore fields, and so boosts.
+    fn score_boosted(
+        &self,
+        id: &u32,
+        terms: &[QueryTerm],
+        idf: &HashMap<String, f32>,
+        boosts: &HashMap<String, f32>,
+    ) -> f32 {
+        if boosts.is_empty() {
+            return self.score_document(id, terms, idf);
+        }
+        if !self.is_candidate(id, terms) {
+            return 0.0;
+        }
+
+        match self.score_mode {
+            ScoreMode::Sum => terms
+                .iter()
+                .map(|t| {
+                    let idf = idf.get(&t.token).copied().unwrap_or(0.0);
+                    self.get_boosted_tf(id, t, boosts) * idf * t.weight
+                })
+                .sum::<f32>(),
+            ScoreMode::BestField => self
+                .field_scores(id, terms, idf, boosts)
+                .fold(0.0, f32::max),
+            ScoreMode::DisMax { tie_breaker } => {
+                let (best, total) = self
+                    .field_scores(id, terms, idf, boosts)
+                    .fold((0.0, 0.0), |(best, total), score| {
+                        (f32::max(best, score), total + score)
+                    });
+                best + tie_breaker * (total - best)
+            }
+            ScoreMode::Overlap | ScoreMode::Jaccard | ScoreMode::Coverage => {
+                self.score_document(id, terms, idf)
+            }
+        }
+    }
+
+    /// TF-IDF of the query within each field on its own, with TF relative to
+    /// the field's length rather than the whole document's.
+    fn field_scores<'a>(
+        &'a self,
+        id: &u32,
+        terms: &'a [QueryTerm],
+        idf: &'a HashMap<String, f32>,
+        boosts: &'a HashMap<String, f32>,
+    ) -> impl Iterator<Item = f32> + 'a {
+        self.field_tf
+            .get(id)
+            .into_iter()
+            .flatten()
+            .map(move |(field, tf)| {
+                let length = tf.values().sum::<u32>();
+                if length == 0 {
+                    return 0.0;
+                }
+
+                terms
+                    .iter()
+                    .filter(|t| t.field.as_ref().is_none_or(|p| path_covers(p, &field.path)))
+                    .map(|t| {
+                        let Some(term) = self.term_id(&t.token) else {
+                            return 0.0;
+                        };
+                        let count = tf.get(&term).copied().unwrap_or(0);
+                        let idf = if self.per_field_idf {
+                            self.field_idf(&field.path, term)
+                        } else {
+                            idf.get(&t.token).copied().unwrap_or(0.0)
+                        };
+                        count as f32 / length as f32 * idf * t.weight
+                    })
+                    .sum::<f32>()
+                    * boost_for(boosts, &field.path)
+            })
+    }
+
+    fn ensure_indexed(&self) -> Result<()> {
+        if self.tf.is_empty() && !self.entries.is_empty() {
+            bail!(
+                "Index not built: {} entries added but none tokenized, call tokenize_entries before searching",
+                self.entries.len()
+            );
+        }
+
+        Ok(())
+    }
+
+    fn demote(&self, entry: &serde_json::Value, mut score: f32) -> f32 {
+        for (filter, factor) in &self.demotions {
+            if filter.matches(entry) {
+                score *= factor;
+            }
+        }
+
+        score
+    }
+
+    /// Scores every indexed document for `query`, zeros included, in no
+    /// particular order. The scores are the ones `search` ranks by, demotions
+    /// applied, which makes this handy for looking at score distributions when
+    /// picking cutoffs.
+    pub fn score_all(&self, query: &str) -> Result<Vec<(u32, f32)>> {
+        self.ensure_indexed()?;
+
+        let terms = self.query_terms(query)?;
+        let idf = self.query_idf(&terms);
+        let mut scores = Vec::with_capacity(self.tf.len());
+        for id in self.tf.keys() {
+            let mut score = self.score_document(id, &terms, &idf);
+            if !self.demotions.is_empty() {
+                let entry = self
+                    .get(*id)
+                    .with_context(|| format!("Failed to find entry {}", id))?;
+                score = self.demote(&entry, score);
+            }
+            scores.push((*id, score));
+        }
+
+        Ok(scores)
+    }
+
+    /// Writes one JSON line per (query, document) pair with `score_all`'s
+    /// score: `{"query": ..., "id": ..., "score": ..., "entry": ...}`. Lines
+    /// are grouped by query, in the order given, then by ascending id. With
+    /// `positive_only`, documents scoring 0.0 are left out. Returns the number
+    /// of lines written.
+    pub fn export_scores<W: Write>(
+        &self,
+        queries: &[&str],
+        mut writer: W,
+        positive_only: bool,
+    ) -> Result<usize> {
+        let mut count = 0;
+        for query in queries {
+            let mut scores = self.score_all(query)?;
+            scores.sort_by_key(|(id, _)| *id);
+
+            for (id, score) in scores {
+                if positive_only && score <= 0.0 {
+                    continue;
+                }
+
+                let entry = self
+                    .get(id)
+                    .with_context(|| format!("Failed to find entry {}", id))?;
+                let line = serde_json::json!({
+                    "query": query,
+                    "id": id,
+                    "score": score,
+                    "entry": entry,
+                });
+                serde_json::to_writer(&mut writer, &line).context("Failed to write scores")?;
+                writer.write_all(b"\n").context("Failed to write scores")?;
+                count += 1;
+            }
+        }
+
+        Ok(count)
+    }
+
+    fn rank<F>(&self, deadline: Option<Instant>, score: F) -> Result<Vec<(u32, f32)>>
+    where
+        F: Fn(&u32) -> f32,
+    {
+        self.rank_ids(self.tf.keys(), deadline, score)
+    }
+
+    /// `rank` over the documents in `ids`. Entries are only fetched when
+    /// demotions or tie breakers need them; otherwise ranking uses the index
+    /// alone and callers fetch just the results they keep.
+    fn rank_ids<'a, I, F>(
+        &self,
+        ids: I,
+        deadline: Option<Instant>,
+        score: F,
+    ) -> Result<Vec<(u32, f32)>>
+    where
+        I: IntoIterator<Item = &'a u32>,
+        F: Fn(&u32) -> f32,
+    {
+        let uses_tie_breakers =
+            self.tie_policy == TiePolicy::TieBreakers && !self.tie_breakers.is_empty();
+        if !self.demotions.is_empty() || uses_tie_breakers {
+            return self.rank_with(ids, deadline, score, |_, _, score| Some(score));
+        }
+
+        self.ensure_indexed()?;
+
+        let mut result = Vec::new();
+        for (i, id) in ids.into_iter().enumerate() {
+            if let Some(deadline) = deadline {
+                if i % DEADLINE_CHECK_INTERVAL == 0 && Instant::now() >= deadline {
+                    bail!("Search exceeded its deadline after scoring {} documents", i);
+                }
+            }
+
+            let score = score(id);
+            if !score.is_nan() && score > 0.0 {
+                result.push((*id, score));
+            }
+        }
+        // what `compare_results` does without tie breakers
+        result.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        result.truncate(self.max_results);
+
+        Ok(result)
+    }
+
+    /// `rank` over the documents in `ids`, with `rescore` turning each matching
+    /// document's text score into the value it's ranked by, or `None` to leave
+    /// the document out. Every matching entry is fetched for `rescore`.
+    fn rank_with<'a, I, F, G>(
+        &self,
+        ids: I,
+        deadline: Option<Instant>,
+        score: F,
+        rescore: G,
+    ) -> Result<Vec<(u32, f32)>>
+    where
+        I: IntoIterator<Item = &'a u32>,
+        F: Fn(&u32) -> f32,
+        G: Fn(u32, &serde_json::Value, f32) -> Option<f32>,
+    {
+        self.ensure_indexed()?;
+
+        let mut result = Vec::new();
+        for (i, id) in ids.into_iter().enumerate() {
+            if let Some(deadline) = deadline {
+                if i % DEADLINE_CHECK_INTERVAL == 0 && Instant::now() >= deadline {
+                    bail!("Search exceeded its deadline after scoring {} documents", i);
+                }
+            }
+
+            let score = score(id);
+            if score.is_nan() || score <= 0.0 {
+                continue;
+            }
+
+            let entry = self
+                .get(*id)
+                .with_context(|| format!("Failed to find entry {}", id))?;
+            let Some(score) = rescore(*id, &entry, self.demote(&entry, score)) else {
+                continue;
+            };
+            result.push((*id, entry, score));
+        }
+        result.sort_by(|a, b| {
+            self.compare_results((&a.1, a.2), (&b.1, b.2))
+                .then_with(|| a.0.cmp(&b.0))
+        });
+        result.truncate(self.max_results);
+
+        Ok(result
+            .into_iter()
+            .map(|(id, _, score)| (id, score))
+            .collect())
+    }
+
+    /// Like `search`, but ranks by `rank(id, entry, text_score)` instead of the
+    /// text score alone, e.g. to blend in an external popularity signal. Only
+    /// entries that match the query are passed to `rank`; whatever it returns,
+    /// negative values included, becomes the result's score, except NaN,
+    /// which drops the entry. Results are not cached.
+    pub fn search_with<F>(&self, query: &str, rank: F) -> Result<Vec<(serde_json::Value, f32)>>
+    where
+        F: Fn(u32, &serde_json::Value, f32) -> f32,
+    {
+        let terms = self.query_terms(query)?;
+        let idf = self.query_idf(&terms);
+        let result = self.rank_with(
+            self.tf.keys(),
+            None,
+            |id| self.score_document(id, &terms, &idf),
+            |id, entry, score| Some(rank(id, entry, score)).filter(|score| !score.is_nan()),
+        )?;
+
+        let mut entries = Vec::new();
+        for (id, score) in result {
+            let entry = self
+                .get(id)
+                .with_context(|| format!("Failed to find entry {}", id))?
+                .into_owned();
+            entries.push((entry, score));
+        }
+
+        Ok(entries)
+    }
+
+    /// Ranks every matching entry for `query`. For the same entries, settings
+    /// and query the output is always identical, order included: scores are
+    /// computed in a fixed order and equal scores fall back to the tie breakers
+    /// and then ascending id.
+    ///
+    /// A quoted clause of several tokens, like `"peanut butter"` in `green
+    /// "peanut butter" smoothie`, is a phrase: under either operator, only
+    /// documents with its tokens next to each other and in order match. The
+    /// phrase's tokens are then scored like the bare terms, each on its own,
+    /// and everything is summed into one score, so what a phrase adds is the
+    /// requirement, not extra weight. A phrase has to be within one value: it
+    /// never runs from one field, or one array element, into the next. A
+    /// scoped phrase such as `title:"peanut butter"` has to be within a value
+    /// of that field. Folded variants don't form phrases.
+    pub fn search(&mut self, query: &str) -> Result<Vec<(serde_json::Value, f32)>> {
+        if let Some(cached_result) = self.cached_results.get(query) {
+            if cached_result.generation == self.generation
+                && cached_result.expiration > Instant::now()
+            {
+                return Ok(cached_result.result.clone());
+            }
+        }
+
+        let result = if query.trim().is_empty() {
+            self.rank_empty_query(None)?
+        } else {
+            let terms = self.query_terms(query)?;
+            self.score_tokens(&terms, None)?
+        };
+
+        let mut entries = Vec::new();
+        for (id, score) in result {
+            let entry = self
+                .get(id)
+                .with_context(|| format!("Failed to find entry {}", id))?
+                .into_owned();
+            entries.push((entry, score));
+        }
+
+        self.cached_results.insert(
+            query.to_string(),
+            CachedResult {
+                result: entries.clone(),
+                expiration: Instant::now() + self.cache_ttl,
+                generation: self.generation,
+            },
+        );
+
+        Ok(entries)
+    }
+
+    /// Like `search`, cache included, but returns `SearchResult`s, which carry
+    /// the id and serialize as objects.
+    pub fn search_results(&mut self, query: &str) -> Result<Vec<SearchResult>> {
+        let mut results = Vec::new();
+        for (entry, score) in self.search(query)? {
+            let id = self
+                .entry_id(&entry)
+                .with_context(|| format!("Failed to get the id of {}", entry))?;
+            results.push(SearchResult { entry, score, id });
+        }
+
+        Ok(results)
+    }
+
+    /// Runs each query and caches its results, so the next `search` for it is
+    /// a cache hit until the cache TTL runs out. Queries that are already
+    /// cached are refreshed.
+    pub fn warm_cache(&mut self, queries: &[&str]) -> Result<()> {
+        for query in queries {
+            self.cached_results.remove(*query);
+            self.search(query)
+                .with_context(|| format!("Failed to warm cache for query {:?}", query))?;
+        }
+
+        Ok(())
+    }
+
+    /// Like `search`, but reports per result how many of the query's distinct
+    /// terms it contains, e.g. to show "matched 2 of 3 terms", and where they
+    /// occur. Results are not cached.
+    pub fn search_detailed(&self, query: &str) -> Result<Vec<DetailedResult>> {
+        let terms = self.query_terms(query)?;
+        let mut distinct: Vec<QueryTerm> = Vec::new();
+        for term in self.parse_terms(query) {
+            if !distinct
+                .iter()
+                .any(|t| t.token == term.token && t.field == term.field)
+            {
+                distinct.push(term);
+            }
+        }
+
+        let mut results = Vec::new();
+        for (id, score) in self.score_tokens(&terms, None)? {
+            let entry = self
+                .get(id)
+                .with_context(|| format!("Failed to find entry {}", id))?
+                .into_owned();
+            let matched_terms = distinct
+                .iter()
+                .filter(|t| self.get_term_tf(&id, t) > 0.0)
+                .count();
+            results.push(DetailedResult {
+                id,
+                entry,
+                score,
+                matched_terms,
+                total_query_terms: distinct.len(),
+                matches: self.match_positions(id, &terms),
+            });
+        }
+
+        Ok(results)
+    }
+
+    fn match_positions(&self, id: u32, terms: &[QueryTerm]) -> HashMap<String, Vec<usize>> {
+        let stream = self.tokens.get(&id).map_or(&[][..], Vec::as_slice);
+        let mut matches = HashMap::new();
+        for term in terms {
+            if matches.contains_key(&term.token) {
+                continue;
+            }
+            let Some(term_id) = self.term_id(&term.token) else {
+                continue;
+            };
+
+            let positions = stream
+                .iter()
+                .enumerate()
+                .filter(|(_, t)| **t == term_id)
+                .map(|(position, _)| position)
+                .collect::<Vec<usize>>();
+            if !positions.is_empty() {
+                matches.insert(term.token.to_string(), positions);
+            }
+        }
+
+        matches
+    }
+
+    /// Like `search`, but deserializes each matching entry into `T`. Fails on
+    /// the first entry that doesn't fit `T`, naming its id. Results are not
+    /// cached.
+    pub fn search_as<T: DeserializeOwned>(&self, query: &str) -> Result<Vec<(T, f32)>> {
+        let terms = self.query_terms(query)?;
+        let mut results = Vec::new();
+        for (id, score) in self.score_tokens(&terms, None)? {
+            let entry = self
+                .get(id)
+                .with_context(|| format!("Failed to find entry {}", id))?;
+            let value = T::deserialize(&*entry)
+                .with_context(|| format!("Failed to deserialize entry {}", id))?;
+            results.push((value, score));
+        }
+
+        Ok(results)
+    }
+
+    /// Runs `search` and buckets the results by the value of `field` (a key or
+    /// dotted path), keeping the best `top_per_group` results of each bucket.
+    /// Groups are ordered by their best score. Entries without the field end up
+    /// in a single group with a `None` key, ordered like any other group.
+    pub fn search_grouped(
+        &mut self,
+        query: &str,
+        field: &str,
+        top_per_group: usize,
+    ) -> Result<Vec<ResultGroup>> {
+        let mut groups: Vec<ResultGroup> = Vec::new();
+        let mut positions = HashMap::new();
+        for (entry, score) in self.search(query)? {
+            let key = get_path(&entry, field).filter(|v| !v.is_null()).cloned();
+            let position = *positions
+                .entry(key.as_ref().map(|v| v.to_string()))
+                .or_insert_with(|| {
+                    groups.push(ResultGroup {
+                        key,
+                        results: Vec::new(),
+                    });
+                    groups.len() - 1
+                });
+
+            let group = &mut groups[position];
+            if group.results.len() < top_per_group {
+                group.results.push((entry, score));
+            }
+        }
+
+        Ok(groups)
+    }
+
+    /// Like `search`, but divides every score by the best one so the top result
+    /// scores 1.0. Normalized scores are only comparable within the results of
+    /// a single query, not across queries.
+    pub fn search_normalized(&mut self, query: &str) -> Result<Vec<(serde_json::Value, f32)>> {
+        let mut result = self.search(query)?;
+        if let Some(max) = result.first().map(|(_, score)| *score) {
+            for (_, score) in &mut result {
+                *score /= max;
+            }
+        }
+
+        Ok(result)
+    }
+}
+
+/// The distinct (token, field) pairs of one query term group, sorted, so the
+/// same group compares equal across queries whatever its group number.
+type GroupKey = Vec<(String, Option<String>)>;
+
+fn group_keys(terms: &[QueryTerm]) -> Vec<(usize, GroupKey)> {
+    let mut groups = terms.iter().map(|t| t.group).collect::<Vec<usize>>();
+    groups.sort();
+    groups.dedup();
+
+    groups
+        .into_iter()
+        .map(|group| {
+            let mut key = terms
+                .iter()
+                .filter(|t| t.group == group)
+                .map(|t| (t.token.to_string(), t.field.clone()))
+                .collect::<GroupKey>();
+            key.sort();
+            key.dedup();
+            (group, key)
+        })
+        .collect()
+}
+
+/// A search-as-you-type session over a `Database`, created with
+/// `Database::incremental_search`. Every call ranks the whole current query
+/// and returns exactly what `search_until` would, ids and scores included.
+///
+/// Under `Operator::And`, a document can only match a query if it contains
+/// every term group, so the session remembers which documents contain all
+/// groups but the last, which may still be mid-word. As long as the next
+/// query keeps those groups, only the remembered documents are scored; any
+/// other edit, or `Operator::Or`, falls back to scoring everything.
+#[derive(Debug)]
+pub struct IncrementalSearch<'a> {
+    db: &'a Database,
+    query: String,
+    /// Groups every document in `candidates` contains, or empty when
+    /// `candidates` isn't in use.
+    base: Vec<GroupKey>,
+    candidates: Vec<u32>,
+}
+
+impl IncrementalSearch<'_> {
+    pub fn query(&self) -> &str {
+        &self.query
+    }
+
+    /// Appends `text` to the query, e.g. the characters typed since the last
+    /// call, and ranks the result.
+    pub fn push(&mut self, text: &str) -> Result<Vec<(u32, f32)>> {
+        self.query.push_str(text);
+        self.run()
+    }
+
+    /// Replaces the query, e.g. after a deletion, and ranks the result.
+    pub fn set_query(&mut self, query: &str) -> Result<Vec<(u32, f32)>> {
+        self.query = query.to_string();
+        self.run()
+    }
+
+    fn run(&mut self) -> Result<Vec<(u32, f32)>> {
+        let db = self.db;
+        if self.query.trim().is_empty() {
+            self.base.clear();
+            self.candidates.clear();
+            return db.rank_empty_query(None);
+        }
+
+        let terms = db.query_terms(&self.query)?;
+        let idf = db.query_idf(&terms);
+        let score = |id: &u32| db.score_document(id, &terms, &idf);
+
+        let mut groups = group_keys(&terms);
+        groups.pop();
+        if db.default_operator != Operator::And || groups.is_empty() {
+            self.base.clear();
+            self.candidates.clear();
+            return db.rank(None, score);
+        }
+
+        // documents missing a remembered group still can't match if the new
+        // query keeps that group, so they never need scoring again
+        let mut added = groups.clone();
+        let reusable = !self.base.is_empty()
+            && self.base.iter().all(|key| {
+                added
+                    .iter()
+                    .position(|(_, k)| k == key)
+                    .map(|i| added.remove(i))
+                    .is_some()
+            });
+        let pool = if reusable {
+            std::mem::take(&mut self.candidates)
+        } else {
+            added = groups.clone();
+            db.tf.keys().copied().collect()
+        };
+
+        let contains = |id: &u32, group: usize| {
+            terms
+                .iter()
+                .filter(|t| t.group == group)
+                .any(|t| db.get_term_tf(id, t) > 0.0)
+        };
+        self.candidates = pool
+            .into_iter()
+            .filter(|id| added.iter().all(|(group, _)| contains(id, *group)))
+            .collect();
+        self.base = groups.into_iter().map(|(_, key)| key).collect();
+
+        db.rank_ids(&self.candidates, None, score)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::analyzer::{LowercaseAnalyzer, NoopAnalyzer};
+
+    fn ids(results: &[(u32, f32)]) -> Vec<u32> {
+        let mut ids = results.iter().map(|(id, _)| *id).collect::<Vec<u32>>();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn max_features_caps_vocabulary() {
+        let mut db = Database::new();
+        db.set_max_features(Some(2));
+        db.add(json!({"id": 1, "text": "apple banana"}));
+        db.add(json!({"id": 2, "text": "apple cherry banana"}));
+        db.add(json!({"id": 3, "text": "apple"}));
+        db.tokenize_entries().unwrap();
+
+        // ids are indexed too, so each id token is a one-document token
+        assert_eq!(db.vocab_stats().unique_tokens, 2);
+        assert!(db.search_until("cherry", far_future()).unwrap().is_empty());
+        assert_eq!(
+            ids(&db.search_until("banana", far_future()).unwrap()),
+            [1, 2]
+        );
+        assert_eq!(db.tokens_for(2).unwrap(), ["APPLE", "BANANA"]);
+    }
+
+    #[test]
+    fn pruned_tokens_keep_positions() {
+        let mut db = Database::new();
+        db.set_max_features(Some(2));
+        db.add(json!({"id": 1, "text": "apple cherry banana"}));
+        db.add(json!({"id": 2, "text": "apple banana"}));
+        db.add(json!({"id": 3, "text": "kiwi"}));
+        db.tokenize_entries().unwrap();
+
+        let detailed = db.search_detailed("apple banana").unwrap();
+        let first = detailed.iter().find(|r| r.id == 1).unwrap();
+        assert_eq!(first.matches["APPLE"], [1]);
+        assert_eq!(first.matches["BANANA"], [3]);
+        assert_eq!(db.document_length(1), Some(4));
+    }
+
+    fn indexed(entries: Vec<serde_json::Value>) -> Database {
+        let mut db = Database::new();
+        for entry in entries {
+            db.add(entry);
+        }
+        db.tokenize_entries().unwrap();
+        db
+    }
+
+    fn search_ids(db: &Database, query: &str) -> Vec<u32> {
+        ids(&db.search_until(query, far_future()).unwrap())
+    }
+
+    #[test]
+    fn quoted_phrase_requires_adjacent_tokens() {
+        let db = indexed(vec![
+            json!({"id": 1, "text": "green peanut butter smoothie"}),
+            json!({"id": 2, "text": "green butter and peanut smoothie"}),
+            json!({"id": 3, "text": "kiwi"}),
+        ]);
+
+        assert_eq!(search_ids(&db, "green peanut butter smoothie"), [1, 2]);
+        assert_eq!(search_ids(&db, "green \"peanut butter\" smoothie"), [1]);
+    }
+
+    #[test]
+    fn quoted_phrase_requires_adjacency_under_and() {
+        let mut db = indexed(vec![
+            json!({"id": 1, "text": "peanut butter"}),
+            json!({"id": 2, "text": "butter peanut"}),
+            json!({"id": 3, "text": "kiwi"}),
+        ]);
+        db.set_default_operator(Operator::And);
+
+        assert_eq!(search_ids(&db, "\"peanut butter\""), [1]);
+    }
+
+    #[test]
+    fn quoted_phrase_stays_within_one_value() {
+        let db = indexed(vec![
+            json!({"id": 1, "a": "crunchy peanut", "b": "butter cookie"}),
+            json!({"id": 2, "tags": ["peanut", "butter"]}),
+            json!({"id": 3, "tags": ["peanut butter"]}),
+            json!({"id": 4, "text": "kiwi"}),
+        ]);
+
+        assert_eq!(search_ids(&db, "\"peanut butter\""), [3]);
+    }
+
+    #[test]
+    fn scoped_phrase_must_be_in_that_field() {
+        let db = indexed(vec![
+            json!({"id": 1, "title": "peanut butter", "body": "jam"}),
+            json!({"id": 2, "title": "jam", "body": "peanut butter"}),
+            json!({"id": 3, "title": "peanut", "body": "butter"}),
+            json!({"id": 4, "text": "kiwi"}),
+        ]);
+
+        assert_eq!(search_ids(&db, "title:\"peanut butter\""), [1]);
+        assert_eq!(search_ids(&db, "body:\"peanut butter\""), [2]);
+    }
+
+    #[test]
+    fn unit_boost_scores_like_no_boost() {
+        let db = indexed(vec![
+            json!({"id": 1, "title": "rust book", "body": "a long text about rust and more"}),
+            json!({"id": 2, "title": "cooking", "body": "rust on pans"}),
+            json!({"id": 3, "title": "kiwi", "body": "fruit"}),
+        ]);
+
+        let plain = db.search_query(&Query::new("rust book")).unwrap();
+        let boosted = db
+            .search_query(&Query::new("rust book").boost("title", 1.0))
+            .unwrap();
+        assert_eq!(ids(&plain), ids(&boosted));
+        for ((a_id, a), (b_id, b)) in plain.iter().zip(&boosted) {
+            assert_eq!(a_id, b_id);
+            assert!((a - b).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn field_boost_reorders_results() {
+        let db = indexed(vec![
+            json!({"id": 1, "title": "rust", "body": "guide"}),
+            json!({"id": 2, "title": "guide", "body": "rust"}),
+            json!({"id": 3, "title": "kiwi", "body": "fruit"}),
+        ]);
+
+        let by_title = db
+            .search_query(&Query::new("rust").boost("title", 3.0))
+            .unwrap();
+        let by_body = db
+            .search_query(&Query::new("rust").boost("body", 3.0))
+            .unwrap();
+        assert_eq!(by_title[0].0, 1);
+        assert_eq!(by_body[0].0, 2);
+        assert!(by_title[0].1 > by_title[1].1);
+    }
+
+    fn with_stop_words(db: &mut Database, name: &str, words: &str) {
+        let path =
+            std::env::temp_dir().join(format!("json-search-{}-{}.txt", name, std::process::id()));
+        std::fs::write(&path, words).unwrap();
+        db.load_stop_words(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn stop_words_are_left_out_of_the_index() {
+        let mut db = Database::new();
+        with_stop_words(&mut db, "index", "the\n\nOf\n");
+        db.add(json!({"id": 1, "text": "the lord of the rings"}));
+        db.add(json!({"id": 2, "text": "kiwi"}));
+        db.tokenize_entries().unwrap();
+
+        assert_eq!(db.tokens_for(1).unwrap(), ["1", "LORD", "RINGS"]);
+        assert_eq!(db.document_frequency("THE"), 0);
+        assert_eq!(search_ids(&db, "the rings"), [1]);
+        assert!(search_ids(&db, "the of").is_empty());
+    }
+
+    #[test]
+    fn diagnose_reports_stop_words() {
+        let mut db = Database::new();
+        with_stop_words(&mut db, "diagnose", "the\nof\n");
+        db.add(json!({"id": 1, "text": "the lord of the rings"}));
+        db.tokenize_entries().unwrap();
+
+        let diagnostics = db.diagnose("the of ...");
+        assert!(diagnostics.tokens.is_empty());
+        let dropped = diagnostics
+            .dropped
+            .iter()
+            .map(|d| (d.text.as_str(), d.reason))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            dropped,
+            [
+                ("THE", DropReason::StopWord),
+                ("OF", DropReason::StopWord),
+                ("...", DropReason::NoTokens),
+            ]
+        );
+    }
+
+    fn recent_corpus(store: bool) -> Database {
+        let mut db = Database::with_config(IndexConfig::default().recency_field("year"));
+        if store {
+            db.set_store(Box::new(crate::store::MemoryStore::default()));
+        }
+        db.add(json!({"id": 1, "year": 2001, "text": "rust rust guide"}));
+        db.add(json!({"id": 2, "year": 2020, "text": "rust guide"}));
+        db.add(json!({"id": 3, "year": 2022, "text": "rust"}));
+        db.add(json!({"id": 4, "year": 2023, "text": "kiwi"}));
+        db.tokenize_entries().unwrap();
+        db
+    }
+
+    #[test]
+    fn store_backed_search_matches_in_memory_search() {
+        let mut memory = recent_corpus(false);
+        let mut stored = recent_corpus(true);
+        assert!(stored.entries.is_empty());
+
+        for db in [&mut memory, &mut stored] {
+            db.add_demotion(Filter::eq("id", json!(3)), 0.5).unwrap();
+        }
+        assert_eq!(
+            memory.search("rust").unwrap(),
+            stored.search("rust").unwrap()
+        );
+        assert_eq!(
+            memory.search_recent("rust", 2010.0).unwrap(),
+            stored.search_recent("rust", 2010.0).unwrap()
+        );
+        assert_eq!(ids(&stored.search_recent("rust", 2010.0).unwrap()), [2, 3]);
+    }
+
+    #[test]
+    fn filter_orders_agree() {
+        let mut entries = Vec::new();
+        for i in 0..40 {
+            let text = if i % 3 == 0 {
+                "rust rust guide"
+            } else {
+                "rust book"
+            };
+            entries.push(json!({"id": i, "category": i % 4, "text": text}));
+        }
+        entries.push(json!({"id": 99, "category": 1, "text": "kiwi"}));
+        let mut db = indexed(entries);
+        db.set_max_results(5);
+
+        let filter = Filter::eq("category", 1);
+        let search = |db: &Database, order| {
+            db.search_query(&Query::new("rust guide").filter(filter.clone(), order))
+                .unwrap()
+        };
+        let before = search(&db, FilterOrder::BeforeScoring);
+        assert_eq!(before.len(), 5);
+        assert_eq!(before, search(&db, FilterOrder::AfterScoring));
+        for (id, _) in &before {
+            assert_eq!(id % 4, 1);
+        }
+
+        db.add_demotion(Filter::eq("text", "rust book"), 0.5)
+            .unwrap();
+        db.set_tie_breakers(vec![SortKey::desc("id")]);
+        assert_eq!(
+            search(&db, FilterOrder::BeforeScoring),
+            search(&db, FilterOrder::AfterScoring)
+        );
+    }
+
+    #[test]
+    fn candidate_count_counts_documents_sharing_any_token() {
+        let mut db = indexed(vec![
+            json!({"id": 1, "text": "apple banana"}),
+            json!({"id": 2, "text": "apple"}),
+            json!({"id": 3, "text": "banana cherry"}),
+            json!({"id": 4, "text": "kiwi"}),
+        ]);
+        db.set_default_operator(Operator::And);
+
+        assert_eq!(db.candidate_count("apple").unwrap(), 2);
+        assert_eq!(db.candidate_count("apple banana").unwrap(), 3);
+        assert_eq!(db.candidate_count("text:cherry apple").unwrap(), 3);
+        assert_eq!(db.candidate_count("durian").unwrap(), 0);
+        assert_eq!(db.diagnose("apple banana").candidate_count, 3);
+        assert_eq!(search_ids(&db, "apple banana"), [1]);
+    }
+
+    #[test]
+    fn shared_synonym_is_expanded_once() {
+        let mut db = indexed(vec![
+            json!({"id": 1, "text": "manual"}),
+            json!({"id": 2, "text": "kiwi"}),
+        ]);
+        let synonyms = HashMap::from([
+            ("guide".to_string(), vec!["manual".to_string()]),
+            ("book".to_string(), vec!["manual".to_string()]),
+        ]);
+        db.set_synonyms(synonyms, 0.5);
+
+        let explanation = db.search_explain("guide book", 1).unwrap();
+        let manual = explanation.terms.iter().filter(|t| t.token == "MANUAL");
+        assert_eq!(manual.count(), 1);
+    }
+
+    #[test]
+    fn field_keys_leave_document_length_alone() {
+        let entries = vec![
+            json!({"id": 1, "text": "apple", "discount": "big sale"}),
+            json!({"id": 2, "text": "kiwi"}),
+        ];
+        let plain = indexed(entries.clone());
+        let mut keyed = Database::with_config(IndexConfig::default().index_field_keys(true));
+        for entry in entries {
+            keyed.add(entry);
+        }
+        keyed.tokenize_entries().unwrap();
+
+        assert_eq!(keyed.document_length(1), plain.document_length(1));
+        assert_eq!(
+            keyed.average_document_length(),
+            plain.average_document_length()
+        );
+        assert_eq!(search_ids(&keyed, "field:discount"), [1]);
+        assert_eq!(
+            keyed.search_until("apple", far_future()).unwrap(),
+            plain.search_until("apple", far_future()).unwrap()
+        );
+
+        keyed.set_max_features(Some(4));
+        keyed.tokenize_entries().unwrap();
+        let mut saved = Vec::new();
+        keyed.save_index(&mut saved).unwrap();
+        let restored = Database::load_index(saved.as_slice()).unwrap();
+        assert_eq!(restored.document_length(1), plain.document_length(1));
+    }
+
+    #[test]
+    fn demotion_factor_must_keep_entries() {
+        let mut db = indexed(vec![
+            json!({"id": 1, "text": "apple", "old": true}),
+            json!({"id": 2, "text": "apple"}),
+            json!({"id": 3, "text": "kiwi"}),
+        ]);
+        for factor in [0.0, -1.0, 1.5, f32::NAN] {
+            assert!(db.add_demotion(Filter::exists("old"), factor).is_err());
+        }
+        assert_eq!(search_ids(&db, "apple"), [1, 2]);
+
+        db.add_demotion(Filter::exists("old"), 0.5).unwrap();
+        let results = db.search_until("apple", far_future()).unwrap();
+        assert_eq!(result_ids(&results), [2, 1]);
+    }
+
+    #[test]
+    fn rebuild_keeps_string_ids_of_remaining_entries() {
+        let mut db = indexed(vec![
+            json!({"id": "a", "text": "apple"}),
+            json!({"id": "b", "text": "banana"}),
+            json!({"id": "c", "text": "kiwi"}),
+        ]);
+        let b = db.id_for_key("b").unwrap();
+        db.entries.retain(|entry| entry["id"] != "a");
+        db.rebuild().unwrap();
+
+        assert_eq!(db.id_for_key("a"), None);
+        assert_eq!(db.id_for_key("b"), Some(b));
+        assert_eq!(search_ids(&db, "banana"), [b]);
+        assert!(search_ids(&db, "apple").is_empty());
+    }
+
+    #[test]
+    fn searching_before_tokenizing_fails() {
+        let mut db = Database::new();
+        assert!(db.search_until("apple", far_future()).unwrap().is_empty());
+
+        db.add(json!({"id": 1, "text": "apple"}));
+        db.add(json!({"id": 2, "text": "kiwi"}));
+        let err = db.search_until("apple", far_future()).unwrap_err();
+        assert!(err.to_string().contains("tokenize_entries"));
+        assert!(db.search("apple").is_err());
+
+        db.tokenize_entries().unwrap();
+        assert_eq!(search_ids(&db, "apple"), [1]);
+    }
+
+    fn result_ids(results: &[(u32, f32)]) -> Vec<u32> {
+        results.iter().map(|(id, _)| *id).collect()
+    }
+
+    #[test]
+    fn tie_breakers_order_equal_scores() {
+        let mut db = indexed(vec![
+            json!({"id": 1, "text": "apple", "year": 2010}),
+            json!({"id": 2, "text": "apple", "year": 2020}),
+            json!({"id": 3, "text": "apple", "era": "modern"}),
+            json!({"id": 4, "text": "apple", "year": 2015}),
+            json!({"id": 5, "text": "kiwi"}),
+        ]);
+        let search = |db: &Database| result_ids(&db.search_until("apple", far_future()).unwrap());
+        assert_eq!(search(&db), [1, 2, 3, 4]);
+
+        db.set_tie_breakers(vec![SortKey::desc("year")]);
+        assert_eq!(search(&db), [2, 4, 1, 3]);
+        db.set_tie_breakers(vec![SortKey::asc("year")]);
+        assert_eq!(search(&db), [1, 4, 2, 3]);
+
+        db.set_tie_policy(TiePolicy::LowestId);
+        assert_eq!(search(&db), [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn remove_and_update_keep_document_frequencies() {
+        let mut db = indexed(vec![
+            json!({"id": 1, "text": "apple banana"}),
+            json!({"id": 2, "text": "apple cherry"}),
+            json!({"id": 3, "text": "banana kiwi"}),
+        ]);
+        assert_eq!(db.document_frequency("APPLE"), 2);
+
+        db.remove(1).unwrap();
+        assert_eq!(db.document_frequency("APPLE"), 1);
+        assert_eq!(db.document_frequency("BANANA"), 1);
+        assert!(db.remove(1).is_none());
+
+        db.update(2, json!({"id": 2, "text": "kiwi durian"}))
+            .unwrap();
+        assert_eq!(db.document_frequency("APPLE"), 0);
+        assert_eq!(db.document_frequency("CHERRY"), 0);
+        assert_eq!(db.document_frequency("KIWI"), 2);
+
+        let fresh = indexed(vec![
+            json!({"id": 2, "text": "kiwi durian"}),
+            json!({"id": 3, "text": "banana kiwi"}),
+        ]);
+        for query in ["kiwi", "banana durian", "apple"] {
+            assert_eq!(
+                db.search_until(query, far_future()).unwrap(),
+                fresh.search_until(query, far_future()).unwrap()
+            );
+        }
+        assert_eq!(db.vocab_stats(), fresh.vocab_stats());
+    }
+
+    #[test]
+    fn scoped_terms_only_match_their_field() {
+        let db = indexed(vec![
+            json!({"id": 1, "title": "the king", "author": {"name": "someone"}}),
+            json!({"id": 2, "title": "a story", "author": {"name": "stephen king"}}),
+            json!({"id": 3, "title": "kiwi", "author": {"name": "nobody"}}),
+        ]);
+
+        assert_eq!(search_ids(&db, "king"), [1, 2]);
+        assert_eq!(search_ids(&db, "title:king"), [1]);
+        assert_eq!(search_ids(&db, "author:king"), [2]);
+        assert_eq!(search_ids(&db, "author.name:king"), [2]);
+        assert!(search_ids(&db, "publisher:king").is_empty());
+    }
+
+    #[test]
+    fn and_operator_requires_every_term() {
+        let mut db = indexed(vec![
+            json!({"id": 1, "text": "red dress"}),
+            json!({"id": 2, "text": "red shoes"}),
+            json!({"id": 3, "text": "crimson dress"}),
+            json!({"id": 4, "text": "kiwi"}),
+        ]);
+        assert_eq!(search_ids(&db, "red dress"), [1, 2, 3]);
+
+        db.set_default_operator(Operator::And);
+        assert_eq!(search_ids(&db, "red dress"), [1]);
+        assert_eq!(search_ids(&db, "red|crimson dress"), [1, 3]);
+        assert!(search_ids(&db, "red durian").is_empty());
+    }
+
+    #[test]
+    fn cached_results_follow_the_generation() {
+        let mut db = indexed(vec![
+            json!({"id": 1, "text": "apple"}),
+            json!({"id": 2, "text": "kiwi"}),
+        ]);
+        let first = db.search("apple").unwrap();
+        let generation = db.generation();
+        assert_eq!(db.search("apple").unwrap(), first);
+        assert_eq!(db.generation(), generation);
+
+        db.add(json!({"id": 3, "text": "apple pie"}));
+        db.tokenize_entries().unwrap();
+        assert!(db.generation() > generation);
+        assert_eq!(db.search("apple").unwrap().len(), 2);
+
+        let generation = db.generation();
+        db.set_default_operator(Operator::And);
+        assert!(db.generation() > generation);
+        let generation = db.generation();
+        db.remove(3).unwrap();
+        assert!(db.generation() > generation);
+        assert_eq!(db.search("apple").unwrap(), first);
+    }
+
+    #[test]
+    fn tokenize_matches_how_documents_are_indexed() {
+        let text = "The Lord-of-the-Rings, rated 4.7!";
+        let mut db = Database::new();
+        with_stop_words(&mut db, "tokenize", "the\n");
+        db.add(json!({"id": 1, "text": text}));
+        db.tokenize_entries().unwrap();
+
+        let tokens = db.tokenize(text);
+        assert_eq!(tokens, ["LORD-OF-THE-RINGS", "RATED", "4.7"]);
+        assert_eq!(db.tokens_for(1).unwrap()[1..], tokens);
+        assert_eq!(db.tokenize_field("text", text), tokens);
+        assert!(db.tokenize("the ...").is_empty());
+    }
+
+    #[test]
+    fn saved_index_round_trips_and_checks_its_header() {
+        let db = indexed(vec![
+            json!({"id": 1, "text": "peanut butter", "tags": ["a", "b"]}),
+            json!({"id": "x", "text": "butter peanut"}),
+            json!({"id": 3, "text": "kiwi"}),
+        ]);
+        let mut saved = Vec::new();
+        db.save_index(&mut saved).unwrap();
+        assert_eq!(&saved[..4], INDEX_MAGIC);
+
+        let restored = Database::load_index(saved.as_slice()).unwrap();
+        for query in ["peanut", "\"peanut butter\"", "text:butter"] {
+            assert_eq!(
+                restored.search_until(query, far_future()).unwrap(),
+                db.search_until(query, far_future()).unwrap()
+            );
+        }
+        assert_eq!(restored.id_for_key("x"), db.id_for_key("x"));
+        assert_eq!(restored.vocab_stats(), db.vocab_stats());
+
+        let mut other_version = saved.clone();
+        other_version[4..8].copy_from_slice(&(INDEX_FORMAT_VERSION + 1).to_le_bytes());
+        assert!(Database::load_index(other_version.as_slice()).is_err());
+        assert!(Database::load_index(&saved[4..]).is_err());
+        assert!(Database::load_index(&b"JS"[..]).is_err());
+    }
+
+    #[test]
+    fn search_document_ranks_by_cosine_similarity() {
+        let db = indexed(vec![
+            json!({"id": 1, "text": "peanut butter smoothie"}),
+            json!({"id": 2, "text": "peanut brittle"}),
+            json!({"id": 3, "text": "kiwi"}),
+        ]);
+
+        let results = db
+            .search_document(&json!({"text": "peanut butter smoothie"}), 10)
+            .unwrap();
+        assert_eq!(result_ids(&results), [1, 2]);
+        assert!(results[1].1 < results[0].1);
+
+        let copy = db
+            .search_document(&json!({"id": 1, "text": "peanut butter smoothie"}), 1)
+            .unwrap();
+        assert_eq!(copy[0].0, 1);
+        assert!((copy[0].1 - 1.0).abs() < 1e-5);
+        assert!(db
+            .search_document(&json!({"text": "durian"}), 10)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn tokenize_entries_is_idempotent() {
+        let mut db = indexed(vec![
+            json!({"id": 1, "text": "apple banana"}),
+            json!({"id": 2, "text": "apple"}),
+            json!({"id": 3, "text": "kiwi"}),
+        ]);
+        let results = db.search_until("apple banana", far_future()).unwrap();
+        let stats = db.vocab_stats();
+        let average = db.average_document_length();
+
+        db.tokenize_entries().unwrap();
+        db.tokenize_entries().unwrap();
+        assert_eq!(
+            db.search_until("apple banana", far_future()).unwrap(),
+            results
+        );
+        assert_eq!(db.vocab_stats(), stats);
+        assert_eq!(db.document_frequency("APPLE"), 2);
+        assert_eq!(db.average_document_length(), average);
+
+        // entries edited in place are picked up by the next call
+        db.entries[1] = json!({"id": 2, "text": "banana"});
+        db.tokenize_entries().unwrap();
+        assert_eq!(db.document_frequency("APPLE"), 1);
+        assert_eq!(db.document_frequency("BANANA"), 2);
+        assert_eq!(search_ids(&db, "banana"), [1, 2]);
+    }
+
+    #[test]
+    fn incremental_search_matches_full_search() {
+        let mut db = indexed(vec![
+            json!({"id": 1, "text": "rust async guide"}),
+            json!({"id": 2, "text": "rust book"}),
+            json!({"id": 3, "text": "async rust runtime"}),
+            json!({"id": 4, "text": "kiwi"}),
+        ]);
+        for operator in [Operator::Or, Operator::And] {
+            db.set_default_operator(operator);
+            let mut session = db.incremental_search();
+            let mut typed = String::new();
+            for c in "rust asy guide".chars() {
+                typed.push(c);
+                let results = session.push(&c.to_string()).unwrap();
+                assert_eq!(session.query(), typed);
+                assert_eq!(results, db.search_until(&typed, far_future()).unwrap());
+            }
+            for query in ["rust as", "rust async", "book", ""] {
+                assert_eq!(
+                    session.set_query(query).unwrap(),
+                    db.search_until(query, far_future()).unwrap()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn empty_query_handling_is_configurable() {
+        let mut db = indexed(vec![
+            json!({"id": 1, "text": "apple", "year": 2010}),
+            json!({"id": 2, "text": "kiwi", "year": 2020}),
+            json!({"id": 3, "text": "pear"}),
+        ]);
+        let search = |db: &Database, query| db.search_until(query, far_future()).unwrap();
+        assert!(search(&db, "  ").is_empty());
+
+        db.set_empty_query(EmptyQuery::All);
+        assert_eq!(search(&db, ""), [(1, 1.0), (2, 1.0), (3, 1.0)]);
+        assert!(search(&db, " ?! ").is_empty());
+        assert_eq!(
+            result_ids(
+                &db.search_query(
+                    &Query::new("").filter(Filter::exists("year"), FilterOrder::BeforeScoring)
+                )
+                .unwrap()
+            ),
+            [1, 2]
+        );
+
+        db.set_empty_query(EmptyQuery::AllSortedBy(SortKey::desc("year")));
+        assert_eq!(result_ids(&search(&db, "")), [2, 1, 3]);
+    }
+
+    #[test]
+    fn idf_follows_mutations_without_a_rebuild() {
+        let mut db = indexed(vec![
+            json!({"id": 1, "text": "apple"}),
+            json!({"id": 2, "text": "kiwi"}),
+        ]);
+        let idf = |db: &Database| db.search_explain("apple", 1).unwrap().terms[0].idf;
+        let before = idf(&db);
+
+        db.add(json!({"id": 3, "text": "pear"}));
+        db.add(json!({"id": 4, "text": "apple pie"}));
+        db.tokenize_entries().unwrap();
+        db.remove(2).unwrap();
+        let fresh = indexed(vec![
+            json!({"id": 1, "text": "apple"}),
+            json!({"id": 3, "text": "pear"}),
+            json!({"id": 4, "text": "apple pie"}),
+        ]);
+        assert_ne!(idf(&db), before);
+        assert_eq!(idf(&db), idf(&fresh));
+    }
+
+    #[test]
+    fn coverage_scores_the_share_of_matched_tokens() {
+        let mut db = indexed(vec![
+            json!({"id": 1, "text": "apple"}),
+            json!({"id": 2, "text": "apple pie crust"}),
+            json!({"id": 3, "text": "apple apple pie"}),
+            json!({"id": 4, "text": "kiwi"}),
+        ]);
+        db.set_score_mode(ScoreMode::Coverage);
+
+        // ids are indexed too, so they count toward each document's tokens
+        assert_eq!(
+            db.search_until("apple", far_future()).unwrap(),
+            [(1, 0.5), (3, 0.5), (2, 0.25)]
+        );
+        assert_eq!(
+            db.search_until("apple pie", far_future()).unwrap()[0],
+            (3, 0.75)
+        );
+    }
+
+    #[test]
+    fn derived_fields_are_searchable_but_not_stored() {
+        let mut db = Database::new();
+        db.add_derived_field("price_range", |entry| {
+            let price = entry.get("price")?.as_f64()?;
+            Some(if price < 10.0 { "cheap" } else { "pricey" }.to_string())
+        });
+        db.add(json!({"id": 1, "title": "dune", "price": 8}));
+        db.add(json!({"id": 2, "title": "emma", "price": 25}));
+        db.add(json!({"id": 3, "title": "free"}));
+        db.tokenize_entries().unwrap();
+
+        assert_eq!(search_ids(&db, "price_range:cheap"), [1]);
+        assert_eq!(search_ids(&db, "pricey"), [2]);
+        assert!(db.entries.iter().all(|e| e.get("price_range").is_none()));
+        assert_eq!(db.tokens_for(3).unwrap(), ["3", "FREE"]);
+    }
+
+    #[test]
+    fn search_results_serialize_as_objects() {
+        let mut db = indexed(vec![
+            json!({"id": 1, "text": "apple"}),
+            json!({"id": 2, "text": "kiwi"}),
+        ]);
+        let results = db.search_results("apple").unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].id, 1);
+        assert_eq!(
+            serde_json::to_value(&results).unwrap(),
+            json!([{"entry": {"id": 1, "text": "apple"}, "score": results[0].score, "id": 1}])
+        );
+    }
+
+    #[test]
+    fn ids_come_from_one_counter() {
+        let keys = ["c", "a", "b"];
+        let build = || {
+            indexed(
+                keys.iter()
+                    .map(|key| json!({"id": key, "text": "apple"}))
+                    .collect(),
+            )
+        };
+        let (first, second) = (build(), build());
+        for (i, key) in keys.iter().enumerate() {
+            assert_eq!(first.id_for_key(key), Some(i as u32 + 1));
+            assert_eq!(second.id_for_key(key), first.id_for_key(key));
+        }
+
+        let mut db = indexed(vec![json!({"id": 10, "text": "apple"})]);
+        assert_eq!(db.add_typed(&json!({"text": "kiwi"})).unwrap(), 11);
+        db.remove(11).unwrap();
+        assert_eq!(db.add_typed(&json!({"id": 3, "text": "pear"})).unwrap(), 12);
+    }
+
+    #[test]
+    fn detailed_results_report_matched_terms_and_positions() {
+        let db = indexed(vec![
+            json!({"id": 1, "text": "rust guide to rust"}),
+            json!({"id": 2, "text": "async book"}),
+            json!({"id": 3, "text": "kiwi"}),
+        ]);
+        let detailed = db.search_detailed("rust async durian").unwrap();
+        assert_eq!(detailed.len(), 2);
+
+        let first = detailed.iter().find(|r| r.id == 1).unwrap();
+        assert_eq!((first.matched_terms, first.total_query_terms), (1, 3));
+        assert_eq!(first.matches["RUST"], [1, 4]);
+        assert!(!first.matches.contains_key("ASYNC"));
+        let second = detailed.iter().find(|r| r.id == 2).unwrap();
+        assert_eq!(second.matches["ASYNC"], [1]);
+        assert_eq!(second.entry, json!({"id": 2, "text": "async book"}));
+    }
+
+    #[test]
+    fn remove_by_matches_values_like_filters() {
+        let mut db = indexed(vec![
+            json!({"id": 1, "text": "apple", "sku": 7}),
+            json!({"id": 2, "text": "apple", "sku": 7.0}),
+            json!({"id": 3, "text": "apple", "meta": {"sku": 7}}),
+            json!({"id": 4, "text": "kiwi"}),
+        ]);
+
+        assert_eq!(db.remove_by("sku", &json!(7)), 2);
+        assert_eq!(search_ids(&db, "apple"), [3]);
+        assert_eq!(db.remove_by("meta.sku", &json!(7)), 1);
+        assert_eq!(db.remove_by("sku", &json!(7)), 0);
+        assert!(search_ids(&db, "apple").is_empty());
+        assert_eq!(db.document_frequency("APPLE"), 0);
+    }
+
+    #[test]
+    fn upsert_replaces_by_natural_key() {
+        let mut db = indexed(vec![
+            json!({"id": 1, "isbn": "a", "text": "apple"}),
+            json!({"id": 2, "isbn": "b", "text": "kiwi"}),
+            json!({"id": 3, "isbn": "b", "text": "kiwi copy"}),
+        ]);
+
+        let id = db
+            .upsert("isbn", json!({"isbn": "a", "text": "pear"}))
+            .unwrap();
+        assert_eq!(id, 1);
+        assert_eq!(db.get(1).unwrap()["id"], 1);
+        assert!(search_ids(&db, "apple").is_empty());
+        assert_eq!(search_ids(&db, "pear"), [1]);
+
+        assert_eq!(
+            db.upsert("isbn", json!({"isbn": "b", "text": "plum"}))
+                .unwrap(),
+            2
+        );
+        assert!(db.get(3).is_none());
+        assert_eq!(db.document_frequency("KIWI"), 0);
+
+        let id = db
+            .upsert("isbn", json!({"isbn": "c", "text": "fig"}))
+            .unwrap();
+        assert_eq!(id, 4);
+        assert_eq!(search_ids(&db, "fig"), [4]);
+        assert!(db.upsert("isbn", json!({"text": "no key"})).is_err());
+        assert!(db
+            .upsert("isbn", json!({"id": 1, "isbn": "d", "text": "clash"}))
+            .is_err());
+    }
+
+    #[test]
+    fn normalized_numbers_match_whatever_their_spelling() {
+        let mut db = Database::with_config(IndexConfig::default().normalize_numbers(true));
+        db.add(json!({"id": 1, "price": 15.0, "text": "rated 4.70"}));
+        db.add(json!({"id": 2, "price": 16, "text": "rated 4.07"}));
+        db.tokenize_entries().unwrap();
+
+        assert_eq!(db.tokens_for(1).unwrap(), ["1", "15", "RATED", "4.7"]);
+        assert_eq!(search_ids(&db, "15.00"), [1]);
+        assert_eq!(search_ids(&db, "4.7"), [1]);
+        assert_eq!(search_ids(&db, "4.070"), [2]);
+
+        let plain = indexed(vec![
+            json!({"id": 1, "text": "4.70"}),
+            json!({"id": 2, "text": "x"}),
+        ]);
+        assert!(search_ids(&plain, "4.7").is_empty());
+    }
+
+    #[test]
+    fn remove_where_reports_removed_ids() {
+        let mut db = indexed(vec![
+            json!({"id": 4, "text": "apple", "year": 2001}),
+            json!({"id": 2, "text": "apple", "year": 2020}),
+            json!({"id": 1, "text": "apple", "year": 1999}),
+            json!({"id": 3, "text": "kiwi", "year": 2024}),
+        ]);
+        let old = Filter::lt("year", 2010);
+
+        assert_eq!(db.remove_where(|entry| old.matches(entry)), [1, 4]);
+        assert!(db.remove_where(|entry| old.matches(entry)).is_empty());
+        assert_eq!(search_ids(&db, "apple"), [2]);
+        assert_eq!(db.document_frequency("APPLE"), 1);
+
+        db.retain(|entry| entry["text"] == "kiwi");
+        assert_eq!(db.entries, [json!({"id": 3, "text": "kiwi", "year": 2024})]);
+    }
+
+    #[test]
+    fn vocab_stats_over_a_known_corpus() {
+        assert_eq!(
+            Database::new().vocab_stats(),
+            VocabStats {
+                unique_tokens: 0,
+                mean_document_frequency: 0.0,
+                median_document_frequency: 0.0,
+                hapax_tokens: 0,
+            }
+        );
+
+        let db = indexed(vec![
+            json!({"id": 1, "text": "apple banana"}),
+            json!({"id": 2, "text": "apple"}),
+            json!({"id": 3, "text": "apple kiwi kiwi"}),
+        ]);
+        // the three ids, BANANA and KIWI are in one document each, APPLE in all
+        assert_eq!(
+            db.vocab_stats(),
+            VocabStats {
+                unique_tokens: 6,
+                mean_document_frequency: 8.0 / 6.0,
+                median_document_frequency: 1.0,
+                hapax_tokens: 5,
+            }
+        );
+    }
+
+    #[test]
+    fn idf_floor_keeps_ubiquitous_tokens_scoring() {
+        let mut db = indexed(vec![
+            json!({"id": 1, "text": "apple apple"}),
+            json!({"id": 2, "text": "apple kiwi"}),
+        ]);
+        assert!(search_ids(&db, "apple").is_empty());
+        let kiwi = db.search_until("kiwi", far_future()).unwrap();
+
+        db.set_idf_floor(0.5);
+        let results = db.search_until("apple", far_future()).unwrap();
+        assert_eq!(result_ids(&results), [1, 2]);
+        assert!(results[0].1 > results[1].1);
+        assert_eq!(db.search_until("kiwi", far_future()).unwrap(), kiwi);
+        assert!(search_ids(&db, "durian").is_empty());
+
+        db.set_idf_floor(-1.0);
+        assert!(search_ids(&db, "apple").is_empty());
+    }
+
+    #[test]
+    fn results_to_csv_writes_fields_and_scores() {
+        let results = [
+            SearchResult {
+                entry: json!({"id": 1, "title": "Dune, Part 1", "author": {"name": "Frank"}}),
+                score: 0.5,
+                id: 1,
+            },
+            SearchResult {
+                entry: json!({"id": 2, "title": null, "tags": ["a", "b"]}),
+                score: 0.25,
+                id: 2,
+            },
+        ];
+        let mut csv = Vec::new();
+        results_to_csv(&results, &["title", "author.name", "tags"], &mut csv).unwrap();
+
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "title,author.name,tags,score\n\
+             \"Dune, Part 1\",Frank,,0.5\n\
+             ,,\"[\"\"a\"\",\"\"b\"\"]\",0.25\n"
+        );
+    }
+
+    #[test]
+    fn positional_arrays_index_elements_under_their_position() {
+        let entries = vec![
+            json!({"id": 1, "tags": ["rust", "async"]}),
+            json!({"id": 2, "tags": ["async", "rust"]}),
+            json!({"id": 3, "tags": ["kiwi"]}),
+        ];
+        let mut db = Database::with_config(IndexConfig::default().positional_arrays(true));
+        for entry in entries.clone() {
+            db.add(entry);
+        }
+        db.tokenize_entries().unwrap();
+
+        assert_eq!(search_ids(&db, "tags.0:rust"), [1]);
+        assert_eq!(search_ids(&db, "tags:rust"), [1, 2]);
+        let boosted = db
+            .search_query(&Query::new("rust").boost("tags.1", 2.0))
+            .unwrap();
+        assert_eq!(result_ids(&boosted), [2, 1]);
+
+        let plain = indexed(entries);
+        assert!(search_ids(&plain, "tags.0:rust").is_empty());
+    }
+
+    #[test]
+    fn estimated_memory_grows_with_the_index() {
+        let empty = Database::new().estimated_memory_bytes();
+        let mut db = Database::new();
+        for i in 0..50 {
+            db.add(json!({"id": i, "text": format!("document number {} about rust", i)}));
+        }
+        let unindexed = db.estimated_memory_bytes();
+        db.tokenize_entries().unwrap();
+        let indexed = db.estimated_memory_bytes();
+        assert!(empty < unindexed && unindexed < indexed);
+
+        db.retain(|entry| entry["id"].as_u64() < Some(5));
+        db.compact();
+        assert!(db.estimated_memory_bytes() < indexed);
+    }
+
+    fn save(db: &Database) -> Vec<u8> {
+        let mut saved = Vec::new();
+        db.save_index(&mut saved).unwrap();
+        saved
+    }
+
+    #[test]
+    fn saved_index_keeps_its_analyzers() {
+        let mut db = Database::with_analyzer(IndexConfig::default(), Box::new(LowercaseAnalyzer));
+        db.set_field_analyzer("code", Box::new(NoopAnalyzer));
+        db.add(json!({"id": 1, "text": "Stephen King", "code": "AB-12"}));
+        db.add(json!({"id": 2, "text": "kiwi", "code": "CD-34"}));
+        db.tokenize_entries().unwrap();
+
+        let restored = Database::load_index(save(&db).as_slice()).unwrap();
+        assert_eq!(restored.tokenize("KING"), ["king"]);
+        assert_eq!(restored.tokenize_field("code", "AB-12"), ["AB-12"]);
+        for query in ["KING", "code:AB-12", "code:ab"] {
+            assert_eq!(
+                restored.search_until(query, far_future()).unwrap(),
+                db.search_until(query, far_future()).unwrap()
+            );
+        }
+        assert_eq!(search_ids(&restored, "code:AB-12"), [1]);
+
+        let json = serde_json::to_string(&db).unwrap();
+        let deserialized = serde_json::from_str::<Database>(&json).unwrap();
+        assert_eq!(search_ids(&deserialized, "king"), [1]);
+    }
+
+    #[derive(Debug)]
+    struct Reversed;
+
+    impl Analyzer for Reversed {
+        fn analyze(&self, text: &str) -> Vec<String> {
+            LexerAnalyzer
+                .analyze(text)
+                .into_iter()
+                .map(|token| token.chars().rev().collect())
+                .collect()
+        }
+    }
+
+    #[test]
+    fn custom_analyzers_and_derived_fields_must_be_restored() {
+        let mut db = Database::with_analyzer(IndexConfig::default(), Box::new(Reversed));
+        db.add_derived_field("shout", |entry| Some(entry["text"].as_str()?.to_string()));
+        db.add(json!({"id": 1, "text": "apple"}));
+        db.add(json!({"id": 2, "text": "kiwi"}));
+        db.tokenize_entries().unwrap();
+        let saved = save(&db);
+
+        assert!(Database::load_index(saved.as_slice()).is_err());
+        assert!(serde_json::from_str::<Database>(&serde_json::to_string(&db).unwrap()).is_err());
+        let without_derived =
+            Database::load_index_with(saved.as_slice(), |db| db.set_analyzer(Box::new(Reversed)));
+        assert!(without_derived.is_err());
+        let wrong_analyzer = Database::load_index_with(saved.as_slice(), |db| {
+            db.set_analyzer(Box::new(LowercaseAnalyzer));
+            db.add_derived_field("shout", |_| None);
+        });
+        assert!(wrong_analyzer.is_err());
+
+        let restored = Database::load_index_with(saved.as_slice(), |db| {
+            db.set_analyzer(Box::new(Reversed));
+            db.add_derived_field("shout", |entry| Some(entry["text"].as_str()?.to_string()));
+        })
+        .unwrap();
+        assert_eq!(restored.tokenize("apple"), ["ELPPA"]);
+        assert_eq!(search_ids(&restored, "apple"), [1]);
+        assert_eq!(search_ids(&restored, "shout:kiwi"), [2]);
+    }
+
+    #[test]
+    fn search_tokens_takes_tokens_from_tokenize() {
+        let mut db = Database::with_analyzer(IndexConfig::default(), Box::new(LowercaseAnalyzer));
+        db.add(json!({"id": 1, "text": "Stephen King"}));
+        db.add(json!({"id": 2, "text": "kiwi"}));
+        db.tokenize_entries().unwrap();
+        let restored = Database::load_index(save(&db).as_slice()).unwrap();
+
+        let tokens = restored.tokenize("KING");
+        assert_eq!(result_ids(&restored.search_tokens(&tokens).unwrap()), [1]);
+        assert!(restored
+            .search_tokens(&["KING".to_string()])
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn query_limits_reject_oversized_queries() {
+        let mut db = indexed(vec![
+            json!({"id": 1, "text": "apple"}),
+            json!({"id": 2, "text": "kiwi"}),
+        ]);
+        db.set_query_limits(11, 2);
+
+        let at_limit = "apple apple";
+        assert_eq!(search_ids(&db, at_limit), [1]);
+        assert_eq!(db.search_detailed(at_limit).unwrap().len(), 1);
+        assert!(db.search(at_limit).is_ok());
+
+        let too_long = "apple  apple";
+        let too_many = "a b c";
+        for query in [too_long, too_many] {
+            assert!(db.search_until(query, far_future()).is_err());
+            assert!(db.search_detailed(query).is_err());
+            assert!(db.search(query).is_err());
+        }
+
+        db.set_query_limits(DEFAULT_MAX_QUERY_LENGTH, DEFAULT_MAX_QUERY_TOKENS);
+        assert!(db.search_detailed(&"a ".repeat(256)).is_ok());
+        assert!(db.search_detailed(&"a ".repeat(257)).is_err());
+        assert!(db.search_detailed(&"a".repeat(4097)).is_err());
+    }
+
+    #[test]
+    fn remove_where_removes_many_documents_at_once() {
+        for store in [false, true] {
+            let mut db = Database::new();
+            if store {
+                db.set_store(Box::new(crate::store::MemoryStore::default()));
+            }
+            for i in 0..200 {
+                db.add(json!({"id": i, "text": if i % 2 == 0 { "apple" } else { "kiwi" }}));
+            }
+            db.tokenize_entries().unwrap();
+            // staged, so in `entries` even with a store
+            db.add(json!({"id": 200, "text": "apple"}));
+            db.add(json!({"id": 201, "text": "kiwi"}));
+
+            let removed = db.remove_where(|entry| entry["text"] == "apple");
+            assert_eq!(removed, (0..=200).step_by(2).collect::<Vec<u32>>());
+            assert_eq!(db.document_frequency("APPLE"), 0);
+            assert_eq!(db.document_frequency("KIWI"), 100);
+            assert!(db.get(0).is_none());
+            assert_eq!(db.get(201).unwrap()["text"], "kiwi");
+            for i in (1..200).step_by(2) {
+                assert_eq!(db.get(i).unwrap()["id"], i);
+            }
+            assert_eq!(db.remove_by("text", &json!("kiwi")), 101);
+            assert!(db.get(1).is_none() && db.get(201).is_none());
+        }
+    }
+
+    #[test]
+    fn index_stream_indexes_json_lines() {
+        let mut db = Database::with_config(IndexConfig::default().max_depth(2));
+        let lines = "{\"id\": \"a\", \"text\": \"apple\"}\n\n\
+            {\"id\": \"b\", \"text\": \"apple kiwi\"}\n\
+            {\"id\": \"c\", \"text\": {\"deep\": {\"deeper\": [\"apple\"]}}}\n";
+
+        let error = db.index_stream(lines.as_bytes()).unwrap_err();
+        assert_eq!(format!("{}", error), "Failed to index line 4");
+        assert_eq!(search_ids(&db, "kiwi"), [2]);
+        assert_eq!(db.id_for_key("c"), None);
+        // already indexed, so tokenizing again changes nothing
+        assert_eq!(db.indexed_hashes.len(), 2);
+        let generation = db.generation;
+        db.tokenize_entries().unwrap();
+        assert_eq!(db.generation, generation);
+
+        let count = db.index_stream("{\"id\": \"d\", \"text\": \"kiwi\"}".as_bytes());
+        assert_eq!(count.unwrap(), 1);
+        assert_eq!(db.id_for_key("d"), Some(3));
+        assert_eq!(search_ids(&db, "apple"), [1, 2]);
+    }
+
+    #[test]
+    fn find_by_reads_through_the_store() {
+        let mut db = recent_corpus(true);
+        db.add(json!({"id": 5, "year": 2020, "text": "staged"}));
+
+        let found = db.find_by("year", &json!(2020));
+        assert_eq!(
+            found
+                .iter()
+                .map(|entry| entry["id"].clone())
+                .collect::<Vec<_>>(),
+            [json!(5), json!(2)]
+        );
+        assert!(db.find_by("year", &json!(1990)).is_empty());
+        assert_eq!(
+            recent_corpus(false).find_by("year", &json!(2020)),
+            [Cow::Borrowed(
+                &json!({"id": 2, "year": 2020, "text": "rust guide"})
+            )]
+        );
+    }
+
+    #[test]
+    fn snapshot_layout_is_pinned_to_the_format_version() {
+        // a failure here means the snapshot changed: bump INDEX_FORMAT_VERSION
+        // and update both sides
+        let snapshot = serde_json::to_value(Database::new()).unwrap();
+        let mut fields = snapshot
+            .as_object()
+            .unwrap()
+            .keys()
+            .map(String::as_str)
+            .collect::<Vec<&str>>();
+        fields.sort_unstable();
+        assert_eq!(
+            (INDEX_FORMAT_VERSION, fields),
+            (
+                5,
+                vec![
+                    "analyzer",
+                    "cache_ttl",
+                    "config",
+                    "default_operator",
+                    "demotions",
+                    "derived_fields",
+                    "df",
+                    "empty_query",
+                    "entries",
+                    "field_analyzers",
+                    "field_tf",
+                    "idf_floor",
+                    "idf_variant",
+                    "last_id",
+                    "length_normalization",
+                    "log_base",
+                    "max_features",
+                    "max_query_length",
+                    "max_query_tokens",
+                    "max_results",
+                    "per_field_idf",
+                    "score_mode",
+                    "stop_words",
+                    "string_ids",
+                    "synonym_weight",
+                    "synonyms",
+                    "terms",
+                    "tf",
+                    "tie_breakers",
+                    "tie_policy",
+                    "tokens",
+                    "values",
+                ]
+            )
+        );
+    }
+
+    #[test]
+    fn document_lengths_count_every_indexed_token() {
+        // the id is indexed too, so each length is one more than the words
+        let mut db = indexed(vec![
+            json!({"id": 1, "text": "the quick brown fox"}),
+            json!({"id": 2, "title": "hello", "tags": ["big world", "again"]}),
+            json!({"id": 3, "text": "kiwi"}),
+        ]);
+        assert_eq!(db.document_length(1), Some(5));
+        assert_eq!(db.document_length(2), Some(5));
+        assert_eq!(db.document_length(3), Some(2));
+        assert_eq!(db.document_length(4), None);
+        assert_eq!(db.average_document_length(), 12.0 / 3.0);
+
+        db.update(3, json!({"id": 3, "text": "kiwi kiwi kiwi"}))
+            .unwrap();
+        assert_eq!(db.document_length(3), Some(4));
+        db.remove(1);
+        assert_eq!(db.document_length(1), None);
+        assert_eq!(db.average_document_length(), 9.0 / 2.0);
+        assert_eq!(Database::new().average_document_length(), 0.0);
+    }
+
+    #[test]
+    fn include_and_exclude_fields_pick_what_is_indexed() {
+        let entries = [
+            json!({"id": 1, "title": "apple", "body": "pear", "meta": {"tag": "plum", "note": "fig"}}),
+            json!({"id": 2, "title": "kiwi", "body": "kiwi", "meta": {"tag": "kiwi", "note": "kiwi"}}),
+        ];
+        let build = |config: IndexConfig| {
+            let mut db = Database::with_config(config);
+            for entry in &entries {
+                db.add(entry.clone());
+            }
+            db.tokenize_entries().unwrap();
+            db
+        };
+        let found = |db: &Database| {
+            ["apple", "pear", "plum", "fig"]
+                .into_iter()
+                .filter(|word| search_ids(db, word) == [1])
+                .collect::<Vec<&str>>()
+        };
+
+        let db = build(IndexConfig::default().include_fields(&["id", "title", "meta.tag"]));
+        assert_eq!(found(&db), ["apple", "plum"]);
+        let db = build(IndexConfig::default().exclude_fields(&["meta"]));
+        assert_eq!(found(&db), ["apple", "pear"]);
+        // exclusion wins over inclusion, also for nested paths
+        let db = build(
+            IndexConfig::default()
+                .include_fields(&["id", "meta"])
+                .exclude_fields(&["meta.note"]),
+        );
+        assert_eq!(found(&db), ["plum"]);
+        assert_eq!(db.tokens_for(1).unwrap(), ["1", "PLUM"]);
+    }
+
+    #[test]
+    fn null_values_index_nothing() {
+        let mut db = indexed(vec![
+            json!({"id": 1, "a": null}),
+            json!({"id": 2, "a": "apple", "b": [null, "pear"]}),
+            json!({"id": 3, "a": "kiwi", "b": {"c": null}}),
+        ]);
+        assert_eq!(db.tokens_for(1).unwrap(), ["1"]);
+        assert_eq!(db.tokens_for(2).unwrap(), ["APPLE", "PEAR", "2"]);
+        assert_eq!(search_ids(&db, "apple"), [2]);
+        assert_eq!(search_ids(&db, "pear"), [2]);
+        assert!(search_ids(&db, "null").is_empty());
+        assert_eq!(db.search("kiwi").unwrap()[0].0["id"], 3);
+    }
+
+    #[test]
+    fn search_kind_scores_only_values_of_that_kind() {
+        let db = indexed(vec![
+            json!({"id": 1, "year": 2008, "title": "kiwi"}),
+            json!({"id": 2, "title": "best of 2008"}),
+            json!({"id": 3, "title": "a true story", "flag": true}),
+            json!({"id": 4, "title": "pear", "flag": false}),
+        ]);
+        let kind_ids = |query, kind| result_ids(&db.search_kind(query, kind).unwrap());
+
+        assert_eq!(kind_ids("2008", ValueKind::Number), [1]);
+        assert_eq!(kind_ids("2008", ValueKind::String), [2]);
+        assert!(kind_ids("2008", ValueKind::Bool).is_empty());
+        assert_eq!(kind_ids("true", ValueKind::Bool), [3]);
+        assert_eq!(kind_ids("true", ValueKind::String), [3]);
+        assert_eq!(kind_ids("false", ValueKind::Bool), [4]);
+        assert!(kind_ids("false", ValueKind::String).is_empty());
+        // scopes still apply within the kind
+        assert!(kind_ids("year:2008", ValueKind::String).is_empty());
+        assert_eq!(kind_ids("year:2008", ValueKind::Number), [1]);
+    }
+
+    #[test]
+    fn search_normalized_scales_scores_to_the_best() {
+        let mut db = indexed(vec![
+            json!({"id": 1, "text": "apple apple apple pear"}),
+            json!({"id": 2, "text": "apple pear plum fig"}),
+            json!({"id": 3, "text": "apple kiwi kiwi kiwi"}),
+            json!({"id": 4, "text": "kiwi"}),
+        ]);
+        let raw = db.search("apple pear").unwrap();
+        let normalized = db.search_normalized("apple pear").unwrap();
+
+        assert_eq!(normalized.len(), 3);
+        assert_eq!(normalized[0].1, 1.0);
+        for ((entry, score), (raw_entry, raw_score)) in normalized.iter().zip(&raw) {
+            assert_eq!(entry, raw_entry);
+            assert!((0.0..=1.0).contains(score));
+            assert!((score - raw_score / raw[0].1).abs() < 1e-6);
+        }
+        assert!(normalized[2].1 < normalized[1].1);
+        assert!(db.search_normalized("missing").unwrap().is_empty());
+    }
+
+    #[test]
+    fn synonyms_find_documents_with_only_the_synonym() {
+        let mut db = indexed(vec![
+            json!({"id": 1, "text": "couch"}),
+            json!({"id": 2, "text": "sofa"}),
+            json!({"id": 3, "text": "kiwi"}),
+        ]);
+        assert_eq!(search_ids(&db, "sofa"), [2]);
+
+        db.set_synonyms(HashMap::from([("sofa".into(), vec!["couch".into()])]), 0.5);
+        let result = db.search_until("sofa", far_future()).unwrap();
+        assert_eq!(result_ids(&result), [2, 1]);
+        // same IDF, so the synonym scores at its weight
+        assert!((result[1].1 - 0.5 * result[0].1).abs() < 1e-6);
+        let entries = db.search("sofa").unwrap();
+        assert_eq!(entries[1].0, json!({"id": 1, "text": "couch"}));
+    }
+
+    #[test]
+    fn wordpiece_continuations_are_standalone_pieces() {
+        let db = indexed(vec![
+            json!({"id": 1, "text": "play ##ing"}),
+            json!({"id": 2, "text": "playing"}),
+            json!({"id": 3, "text": "kiwi"}),
+        ]);
+        assert_eq!(db.tokenize("play ##ing"), ["PLAY", "ING"]);
+        assert_eq!(db.tokenize("##ing"), db.tokenize("ing"));
+        assert_eq!(db.tokens_for(1).unwrap(), ["1", "PLAY", "ING"]);
+
+        // index and query strip the marker alike
+        assert_eq!(search_ids(&db, "##ing"), [1]);
+        assert_eq!(search_ids(&db, "ing"), [1]);
+        assert_eq!(search_ids(&db, "playing"), [2]);
+        assert_eq!(search_ids(&db, "\"play ##ing\""), [1]);
+    }
+
+    #[test]
+    fn custom_id_field_identifies_documents() {
+        let mut db = Database::with_config(IndexConfig::default().id_field("sku"));
+        db.add(json!({"sku": 10, "id": 99, "text": "apple"}));
+        db.add(json!({"sku": "pear-1", "text": "pear"}));
+        db.add(json!({"sku": 12, "text": "kiwi"}));
+        db.tokenize_entries().unwrap();
+
+        assert_eq!(db.get(10).unwrap()["text"], "apple");
+        assert!(db.get(99).is_none());
+        // a string key takes the id after the highest one seen so far
+        let pear = db.id_for_key("pear-1").unwrap();
+        assert_eq!(pear, 11);
+        assert_eq!(db.get(pear).unwrap()["sku"], "pear-1");
+        assert_eq!(search_ids(&db, "apple"), [10]);
+        assert_eq!(search_ids(&db, "pear"), [pear]);
+
+        assert_eq!(db.remove(10).unwrap()["id"], 99);
+        assert!(search_ids(&db, "apple").is_empty());
+        // a document without the id field can't be indexed
+        db.add(json!({"id": 20, "text": "plum"}));
+        assert!(db.tokenize_entries().is_err());
+    }
+
+    #[test]
+    fn fold_diacritics_matches_unaccented_queries() {
+        let entries = [
+            json!({"id": 1, "text": "Café Crème"}),
+            json!({"id": 2, "text": "naïve résumé"}),
+            json!({"id": 3, "text": "kiwi"}),
+        ];
+        let build = |fold| {
+            let mut db = Database::with_config(IndexConfig::default().fold_diacritics(fold));
+            for entry in &entries {
+                db.add(entry.clone());
+            }
+            db.tokenize_entries().unwrap();
+            db
+        };
+
+        let db = build(true);
+        assert_eq!(search_ids(&db, "cafe"), [1]);
+        assert_eq!(search_ids(&db, "café"), [1]);
+        assert_eq!(search_ids(&db, "\"cafe creme\""), [1]);
+        assert_eq!(search_ids(&db, "naive resume"), [2]);
+        assert_eq!(db.tokens_for(1).unwrap(), ["1", "CAFE", "CREME"]);
+        assert_eq!(db.get(1).unwrap()["text"], "Café Crème");
+
+        let db = build(false);
+        assert!(search_ids(&db, "cafe").is_empty());
+        assert_eq!(search_ids(&db, "café"), [1]);
+    }
+
+    #[test]
+    fn field_score_modes_rank_differently_from_sum() {
+        // the same length each: 1 has both terms in its title, 2 has more
+        // matches spread over its fields
+        let mut db = indexed(vec![
+            json!({"id": 1, "title": "apple pear", "body": "fig plum lime"}),
+            json!({"id": 2, "title": "apple fig", "body": "pear plum", "tags": "apple"}),
+            json!({"id": 3, "title": "kiwi"}),
+        ]);
+        let mut ranked = |mode| {
+            db.set_score_mode(mode);
+            result_ids(&db.search_until("apple pear", far_future()).unwrap())
+        };
+
+        assert_eq!(ranked(ScoreMode::Sum), [2, 1]);
+        assert_eq!(ranked(ScoreMode::BestField), [1, 2]);
+        assert_eq!(ranked(ScoreMode::DisMax { tie_breaker: 0.0 }), [1, 2]);
+        assert_eq!(ranked(ScoreMode::DisMax { tie_breaker: 1.0 }), [2, 1]);
+    }
+
+    #[test]
+    fn unknown_terms_have_no_idf_and_add_nothing() {
+        let mut db = indexed(vec![
+            json!({"id": 1, "text": "apple pear"}),
+            json!({"id": 2, "text": "apple"}),
+            json!({"id": 3, "text": "kiwi"}),
+        ]);
+        for floor in [0.0, 0.5] {
+            db.set_idf_floor(floor);
+            assert_eq!(db.document_frequency("DURIAN"), 0);
+            assert_eq!(db.get_idf("DURIAN"), 0.0);
+            assert!(db.get_idf("APPLE") > 0.0);
+            assert_eq!(
+                db.search_until("apple durian", far_future()).unwrap(),
+                db.search_until("apple", far_future()).unwrap()
+            );
+            assert!(search_ids(&db, "durian").is_empty());
+        }
+    }
+
+    #[test]
+    fn warm_cache_makes_the_next_search_a_cache_hit() {
+        let mut db = indexed(vec![
+            json!({"id": 1, "text": "apple"}),
+            json!({"id": 2, "text": "kiwi"}),
+        ]);
+        db.warm_cache(&["apple", "kiwi"]).unwrap();
+        assert_eq!(db.cached_results.len(), 2);
+        assert_eq!(db.cached_results["apple"].generation, db.generation);
+
+        // editing entries in place doesn't invalidate, so only a cache hit
+        // still returns the old text
+        db.entries[0] = json!({"id": 1, "text": "apple pie"});
+        assert_eq!(db.search("apple").unwrap()[0].0["text"], "apple");
+        db.warm_cache(&["apple"]).unwrap();
+        assert_eq!(db.search("apple").unwrap()[0].0["text"], "apple pie");
+
+        db.set_query_limits(4, 1);
+        let error = db.warm_cache(&["apple pie"]).unwrap_err();
+        assert_eq!(
+            format!("{}", error),
+            "Failed to warm cache for query \"apple pie\""
+        );
+    }
+
+    #[test]
+    fn overlap_and_jaccard_order_by_shared_tokens() {
+        let mut db = indexed(vec![
+            json!({"id": 1, "text": "apple apple apple apple"}),
+            json!({"id": 2, "text": "apple pear plum fig rye oat"}),
+            json!({"id": 3, "text": "apple pear"}),
+            json!({"id": 4, "text": "kiwi"}),
+        ]);
+        let mut ranked = |mode| {
+            db.set_score_mode(mode);
+            db.search_until("apple pear", far_future()).unwrap()
+        };
+
+        let tf_idf = ranked(ScoreMode::Sum);
+        assert_eq!(result_ids(&tf_idf), [3, 1, 2]);
+        // term counts don't matter, so 1 drops below both documents with pear
+        let overlap = ranked(ScoreMode::Overlap);
+        assert_eq!(overlap, [(2, 2.0), (3, 2.0), (1, 1.0)]);
+        // 2 shares both tokens, but among the 7 distinct ones of the union
+        let jaccard = ranked(ScoreMode::Jaccard);
+        assert_eq!(result_ids(&jaccard), [3, 1, 2]);
+        assert!((jaccard[0].1 - 2.0 / 3.0).abs() < 1e-6);
+        assert!((jaccard[1].1 - 1.0 / 3.0).abs() < 1e-6);
+        assert!((jaccard[2].1 - 2.0 / 7.0).abs() < 1e-6);
+    }
+
+    fn csv_file(name: &str, contents: &str) -> std::path::PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("json-search-{}-{}.csv", name, std::process::id()));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn load_csv_files_counts_ids_across_files() {
+        let header = "Name,Author,User Rating,Reviews,Price,Year,Genre\n";
+        let first = csv_file(
+            "first",
+            &format!(
+                "{}Dune,Frank Herbert,4.5,100,9,1965,Fiction\nBad,Nobody,4.0,oops,1,2000,Fiction\n",
+                header
+            ),
+        );
+        let second = csv_file(
+            "second",
+            &format!("{}Emma,Jane Austen,4.1,\"1,200\",5,1815,Fiction\nBlank,Nobody,,10,1,2000,Fiction\nIt,Stephen King,4.6,900,12,1986,Fiction\n", header),
+        );
+
+        let mut db = Database::new();
+        let reports = db
+            .load_csv_files(&[&first, &second], |record: Record| Entry::from(record))
+            .unwrap();
+        let missing = db
+            .load_csv_files(&[std::path::Path::new("missing.csv")], |record: Record| {
+                Entry::from(record)
+            });
+        std::fs::remove_file(&first).unwrap();
+        std::fs::remove_file(&second).unwrap();
+
+        assert_eq!(reports.len(), 2);
+        assert_eq!(
+            (reports[0].path.as_path(), reports[0].rows),
+            (first.as_path(), 1)
+        );
+        assert_eq!(reports[0].errors.len(), 1);
+        assert!(reports[0].errors[0].starts_with("line 3: "));
+        assert_eq!(reports[1].rows, 2);
+        assert_eq!(reports[1].errors.len(), 1);
+        assert!(reports[1].errors[0].starts_with("line 3: "));
+        let names = db
+            .entries
+            .iter()
+            .map(|entry| {
+                (
+                    entry["id"].as_u64().unwrap(),
+                    entry["name"].as_str().unwrap(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(names, [(1, "Dune"), (2, "Emma"), (3, "It")]);
+        assert_eq!(db.entries[1]["reviews"], 1200);
+        assert!(missing.is_err());
+    }
+
+    #[test]
+    fn equal_queries_serialize_byte_for_byte() {
+        let texts = ["apple", "apple pear", "pear plum", "kiwi"];
+        let entries = (0..50)
+            .map(|i| json!({"id": i, "text": texts[i % 4]}))
+            .collect::<Vec<_>>();
+        // every database hashes with its own random keys
+        let mut first = indexed(entries.clone());
+        let mut second = indexed(entries);
+
+        for query in ["apple pear", "plum | kiwi", "\"apple pear\"", ""] {
+            let a = serde_json::to_vec(&first.search(query).unwrap()).unwrap();
+            let b = serde_json::to_vec(&second.search(query).unwrap()).unwrap();
+            assert_eq!(a, b, "{}", query);
+            first.cached_results.clear();
+            let again = serde_json::to_vec(&first.search(query).unwrap()).unwrap();
+            assert_eq!(a, again, "{}", query);
+        }
+    }
+
+    #[test]
+    fn search_grouped_buckets_by_field() {
+        // every document has five tokens, so scores follow the apple counts
+        let mut db = indexed(vec![
+            json!({"id": 1, "genre": "a", "text": "apple apple apple fig"}),
+            json!({"id": 2, "genre": "b", "text": "apple apple fig fig"}),
+            json!({"id": 3, "genre": "a", "text": "apple fig fig fig"}),
+            json!({"id": 4, "note": "z", "text": "apple apple fig fig"}),
+            json!({"id": 5, "genre": null, "note": "z", "text": "apple fig fig fig"}),
+            json!({"id": 6, "genre": "a", "text": "kiwi"}),
+        ]);
+        let grouped = |db: &mut Database, top| {
+            db.search_grouped("apple", "genre", top)
+                .unwrap()
+                .into_iter()
+                .map(|group| {
+                    let ids = group
+                        .results
+                        .iter()
+                        .map(|(entry, _)| entry["id"].as_u64().unwrap())
+                        .collect::<Vec<u64>>();
+                    (group.key, ids)
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            grouped(&mut db, 10),
+            [
+                (Some(json!("a")), vec![1, 3]),
+                (Some(json!("b")), vec![2]),
+                (None, vec![4, 5]),
+            ]
+        );
+        assert_eq!(
+            grouped(&mut db, 1),
+            [
+                (Some(json!("a")), vec![1]),
+                (Some(json!("b")), vec![2]),
+                (None, vec![4]),
+            ]
+        );
+        assert!(db.search_grouped("missing", "genre", 1).unwrap().is_empty());
+    }
+
+    #[test]
+    fn idf_variants_and_bases_match_hand_computed_values() {
+        use std::f32::consts::{LN_2, LOG10_2};
+
+        // 8 documents: APPLE in 2, PEAR in 4, FIG in all
+        let mut db = indexed(
+            (1..=8)
+                .map(|i| {
+                    let text = match i {
+                        1 | 2 => "apple pear fig",
+                        3 | 4 => "pear fig",
+                        _ => "fig",
+                    };
+                    json!({"id": i, "text": text})
+                })
+                .collect(),
+        );
+        let cases = [
+            // log(8 / 2), log(8 / 4), log(8 / 8)
+            (IdfVariant::Standard, LogBase::E, [1.386_294_4, LN_2, 0.0]),
+            (IdfVariant::Standard, LogBase::Two, [2.0, 1.0, 0.0]),
+            (IdfVariant::Standard, LogBase::Ten, [0.602_06, LOG10_2, 0.0]),
+            // log(1 + 4), log(1 + 2), log(1 + 1)
+            (
+                IdfVariant::Smoothed,
+                LogBase::E,
+                [1.609_438, 1.098_612_3, LN_2],
+            ),
+            (
+                IdfVariant::Smoothed,
+                LogBase::Two,
+                [2.321_928, 1.584_962_5, 1.0],
+            ),
+            (
+                IdfVariant::Smoothed,
+                LogBase::Ten,
+                [0.698_97, 0.477_121_3, LOG10_2],
+            ),
+            // log(6 / 2), log(4 / 4), log(0 / 8) floored
+            (
+                IdfVariant::Probabilistic,
+                LogBase::E,
+                [1.098_612_3, 0.0, 0.0],
+            ),
+            (
+                IdfVariant::Probabilistic,
+                LogBase::Two,
+                [1.584_962_5, 0.0, 0.0],
+            ),
+            (
+                IdfVariant::Probabilistic,
+                LogBase::Ten,
+                [0.477_121_3, 0.0, 0.0],
+            ),
+        ];
+        for (variant, base, expected) in cases {
+            db.set_idf(variant, base);
+            for (token, idf) in ["APPLE", "PEAR", "FIG"].into_iter().zip(expected) {
+                let actual = db.get_idf(token);
+                assert!(
+                    (actual - idf).abs() < 1e-5,
+                    "{:?} {:?} {}: {} != {}",
+                    variant,
+                    base,
+                    token,
+                    actual,
+                    idf
+                );
+            }
+            assert_eq!(db.get_idf("DURIAN"), 0.0);
+        }
+    }
+
+    #[test]
+    fn deep_documents_are_rejected_without_recursing() {
+        // `json!` and dropping both recurse, so build and unwind by hand
+        fn nest(mut value: serde_json::Value, depth: usize) -> serde_json::Value {
+            for _ in 0..depth {
+                value = serde_json::Value::Array(vec![value]);
+            }
+            value
+        }
+        fn unnest(mut value: serde_json::Value) {
+            while let serde_json::Value::Array(mut inner) = value {
+                value = inner.pop().unwrap_or_default();
+            }
+        }
+
+        let mut db = indexed(vec![json!({"id": 1, "text": "apple"})]);
+        let mut entry = json!({"id": 2});
+        entry["text"] = nest(json!("pear"), 100_000);
+        db.add(entry);
+        let error = db.tokenize_entries().unwrap_err();
+        assert_eq!(
+            format!("{}", error),
+            format!(
+                "Entry nests deeper than the maximum depth of {}",
+                DEFAULT_MAX_DEPTH
+            )
+        );
+        assert!(db.tokens_for(2).is_none());
+        unnest(db.entries[1]["text"].take());
+
+        // nothing at the limit is cut off
+        db.entries[1]["text"] = nest(json!("pear"), DEFAULT_MAX_DEPTH - 1);
+        db.tokenize_entries().unwrap();
+        assert_eq!(search_ids(&db, "pear"), [2]);
+    }
+
+    #[test]
+    fn query_idf_scores_like_per_document_idf() {
+        let mut db = indexed(vec![
+            json!({"id": 1, "title": "apple pear", "text": "apple"}),
+            json!({"id": 2, "title": "pear", "text": "couch plum"}),
+            json!({"id": 3, "text": "apple sofa sofa"}),
+            json!({"id": 4, "text": "kiwi"}),
+        ]);
+        db.set_synonyms(HashMap::from([("sofa".into(), vec!["couch".into()])]), 0.5);
+
+        for query in ["apple pear", "title:pear apple", "sofa plum", "apple apple"] {
+            let terms = db.query_terms(query).unwrap();
+            let results = db.search_until(query, far_future()).unwrap();
+            assert!(!results.is_empty());
+            for (id, score) in results {
+                // the IDF looked up again for every document, as before
+                let expected = terms
+                    .iter()
+                    .map(|t| db.get_term_tf(&id, t) * db.get_idf(&t.token) * t.weight)
+                    .sum::<f32>();
+                assert!((score - expected).abs() < 1e-6, "{} {}", query, id);
+            }
+        }
+    }
+
+    #[test]
+    fn lenient_number_reads_hand_written_numbers() {
+        #[derive(Debug, Deserialize)]
+        struct Cell {
+            #[serde(deserialize_with = "lenient_number")]
+            n: u32,
+        }
+        let parse = |raw: &str| {
+            serde_json::from_value::<Cell>(json!({ "n": raw }))
+                .map(|cell| cell.n)
+                .map_err(|e| e.to_string())
+        };
+
+        assert_eq!(parse("1,200"), Ok(1200));
+        assert_eq!(parse(" 1 200 "), Ok(1200));
+        assert_eq!(parse("7"), Ok(7));
+        assert_eq!(parse("N/A"), Err("missing value \"N/A\"".to_string()));
+        assert_eq!(parse("na"), Err("missing value \"na\"".to_string()));
+        assert_eq!(parse(""), Err("missing value \"\"".to_string()));
+        assert_eq!(parse("  "), Err("missing value \"  \"".to_string()));
+        assert!(parse("12x")
+            .unwrap_err()
+            .starts_with("invalid number \"12x\""));
+    }
+
+    #[test]
+    fn export_scores_writes_a_line_per_pair() {
+        let db = indexed(vec![
+            json!({"id": 2, "text": "apple"}),
+            json!({"id": 1, "text": "apple pear"}),
+            json!({"id": 3, "text": "kiwi"}),
+        ]);
+        let lines = |queries: &[&str], positive_only| {
+            let mut out = Vec::new();
+            let count = db.export_scores(queries, &mut out, positive_only).unwrap();
+            let rows = String::from_utf8(out)
+                .unwrap()
+                .lines()
+                .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+                .collect::<Vec<_>>();
+            assert_eq!(count, rows.len());
+            rows
+        };
+
+        let all = lines(&["pear", "apple"], false);
+        let keys = all
+            .iter()
+            .map(|row| (row["query"].as_str().unwrap(), row["id"].as_u64().unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            keys,
+            [
+                ("pear", 1),
+                ("pear", 2),
+                ("pear", 3),
+                ("apple", 1),
+                ("apple", 2),
+                ("apple", 3)
+            ]
+        );
+        let scores = db.score_all("pear").unwrap();
+        let pear = scores.iter().find(|(id, _)| *id == 1).unwrap().1;
+        assert_eq!(all[0]["score"].as_f64().unwrap() as f32, pear);
+        assert_eq!(all[1]["score"], 0.0);
+        assert_eq!(all[0]["entry"], json!({"id": 1, "text": "apple pear"}));
+
+        let positive = lines(&["pear", "apple"], true);
+        assert_eq!(positive.len(), 3);
+        assert!(positive
+            .iter()
+            .all(|row| row["score"].as_f64().unwrap() > 0.0));
+        assert!(lines(&[], false).is_empty());
+    }
+
+    #[test]
+    fn length_normalization_ranks_short_documents_first() {
+        // one apple each; lengths 7, 3 and 2, so the average is 4
+        let mut db = indexed(vec![
+            json!({"id": 1, "text": "apple fig plum lime rye oat"}),
+            json!({"id": 2, "text": "apple pear"}),
+            json!({"id": 3, "text": "kiwi"}),
+        ]);
+        let apple = db.parse_terms("apple").remove(0);
+
+        let result = db.search_until("apple", far_future()).unwrap();
+        assert_eq!(result_ids(&result), [2, 1]);
+        assert!((result[0].1 / result[1].1 - 7.0 / 3.0).abs() < 1e-5);
+        assert_eq!(db.get_term_tf(&2, &apple), 1.0 / 3.0);
+
+        db.set_length_normalization(0.5);
+        assert_eq!(db.get_term_tf(&2, &apple), 1.0 / 3.5);
+        assert_eq!(db.get_term_tf(&1, &apple), 1.0 / 5.5);
+        assert_eq!(
+            result_ids(&db.search_until("apple", far_future()).unwrap()),
+            [2, 1]
+        );
+
+        // without normalization the scores tie and the lower id comes first
+        db.set_length_normalization(0.0);
+        let result = db.search_until("apple", far_future()).unwrap();
+        assert_eq!(result_ids(&result), [1, 2]);
+        assert_eq!(result[0].1, result[1].1);
+    }
+
+    #[test]
+    fn keywords_rank_a_documents_tokens_by_tf_idf() {
+        let db = indexed(vec![
+            json!({"id": 1, "text": "rust rust guide the"}),
+            json!({"id": 2, "text": "python guide the"}),
+            json!({"id": 3, "text": "the kiwi"}),
+        ]);
+        let ln3 = 3f32.ln();
+
+        // the id is a token like any other; THE is in every document
+        let keywords = db.keywords(1, 10);
+        let tokens = keywords.iter().map(|(t, _)| t.as_str()).collect::<Vec<_>>();
+        assert_eq!(tokens, ["RUST", "1", "GUIDE"]);
+        assert!((keywords[0].1 - 2.0 / 5.0 * ln3).abs() < 1e-6);
+        assert!((keywords[1].1 - 1.0 / 5.0 * ln3).abs() < 1e-6);
+        assert!((keywords[2].1 - 1.0 / 5.0 * 1.5f32.ln()).abs() < 1e-6);
+
+        assert_eq!(db.keywords(1, 1), keywords[..1]);
+        assert!(db.keywords(1, 0).is_empty());
+        assert!(db.keywords(9, 5).is_empty());
+    }
+
+    #[test]
+    fn search_with_ranks_by_the_closure() {
+        let db = indexed(vec![
+            json!({"id": 1, "text": "apple apple apple"}),
+            json!({"id": 2, "text": "apple pear"}),
+            json!({"id": 3, "text": "apple pear plum fig"}),
+            json!({"id": 4, "text": "kiwi"}),
+        ]);
+        let by_text = db.search_until("apple", far_future()).unwrap();
+        assert_eq!(result_ids(&by_text), [1, 2, 3]);
+
+        // inverting the text score puts the weakest match first
+        let inverted = db.search_with("apple", |_, _, score| -score).unwrap();
+        let ids = inverted
+            .iter()
+            .map(|(entry, _)| entry["id"].as_u64().unwrap() as u32)
+            .collect::<Vec<u32>>();
+        assert_eq!(ids, [3, 2, 1]);
+        for ((_, score), (_, text_score)) in inverted.iter().zip(by_text.iter().rev()) {
+            assert_eq!(*score, -text_score);
+        }
+
+        // the closure sees the id and entry; NaN drops the entry
+        let picked = db
+            .search_with("apple", |id, entry, _| {
+                assert_eq!(entry["id"], id);
+                if id == 2 {
+                    f32::NAN
+                } else {
+                    id as f32
+                }
+            })
+            .unwrap();
+        assert_eq!(
+            picked.iter().map(|(_, score)| *score).collect::<Vec<f32>>(),
+            [3.0, 1.0]
+        );
+        assert!(db.search_with("durian", |_, _, _| 1.0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn compact_shrinks_collections_after_removals() {
+        let mut db = Database::new();
+        for i in 0..500 {
+            db.add(json!({"id": i, "text": format!("word{} rust", i)}));
+        }
+        db.tokenize_entries().unwrap();
+        db.retain(|entry| entry["id"].as_u64() < Some(5));
+        let before = (
+            db.entries.capacity(),
+            db.terms.capacity(),
+            db.tf.capacity(),
+            db.tokens.capacity(),
+            db.doc_len.capacity(),
+        );
+        assert!(before.0 >= 500 && before.2 >= 500 && before.3 >= 500);
+
+        db.compact();
+        // the lexer splits "word3" into WORD and 3, so the five documents
+        // left use WORD, RUST and their ids
+        assert_eq!(db.terms.len(), 7);
+        assert_eq!(db.terms.capacity(), db.terms.len());
+        assert_eq!(db.entries.capacity(), 5);
+        for (after, before) in [
+            (db.tf.capacity(), before.2),
+            (db.tokens.capacity(), before.3),
+            (db.doc_len.capacity(), before.4),
+        ] {
+            assert!(after < before && after < 20, "{} {}", after, before);
+        }
+        assert!(db.term_ids.capacity() < 20);
+        assert_eq!(search_ids(&db, "word3"), [3]);
+        assert_eq!(db.document_frequency("RUST"), 5);
+    }
+
+    #[test]
+    fn per_field_idf_weighs_tokens_by_their_field() {
+        // APPLE is in three of four bodies but only one title
+        let mut db = indexed(vec![
+            json!({"id": 1, "title": "apple", "body": "apple"}),
+            json!({"id": 2, "title": "pear", "body": "apple"}),
+            json!({"id": 3, "title": "plum", "body": "apple"}),
+            json!({"id": 4, "title": "kiwi", "body": "fig"}),
+        ]);
+        let apple = db.term_id("APPLE").unwrap();
+        assert!((db.get_idf("APPLE") - (4.0f32 / 3.0).ln()).abs() < 1e-6);
+        assert!((db.field_idf("title", apple) - 4f32.ln()).abs() < 1e-6);
+        assert!((db.field_idf("body", apple) - (4.0f32 / 3.0).ln()).abs() < 1e-6);
+
+        let scores = |db: &mut Database, mode, per_field| {
+            db.set_score_mode(mode);
+            db.set_per_field_idf(per_field);
+            db.search_until("apple", far_future()).unwrap()
+        };
+        let global = scores(&mut db, ScoreMode::BestField, false);
+        let per_field = scores(&mut db, ScoreMode::BestField, true);
+        assert_eq!(result_ids(&global), [1, 2, 3]);
+        assert_eq!(result_ids(&per_field), [1, 2, 3]);
+        // the title match counts for more, the body matches the same
+        assert!(per_field[0].1 > global[0].1);
+        assert_eq!(per_field[1].1, global[1].1);
+        assert_eq!(
+            scores(&mut db, ScoreMode::Sum, true),
+            scores(&mut db, ScoreMode::Sum, false)
+        );
+    }
+
+    #[test]
+    fn search_as_deserializes_matching_entries() {
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Book {
+            id: u32,
+            title: String,
+            #[serde(default)]
+            year: Option<u32>,
+        }
+        let db = indexed(vec![
+            json!({"id": 1, "title": "rust guide", "year": 2020, "extra": true}),
+            json!({"id": 2, "title": "rust rust rust"}),
+            json!({"id": 3, "title": "kiwi"}),
+            json!({"id": 4, "title": ["not", "a", "string", "rust"]}),
+        ]);
+
+        let books = db.search_as::<Book>("guide").unwrap();
+        assert_eq!(
+            books,
+            [(
+                Book {
+                    id: 1,
+                    title: "rust guide".into(),
+                    year: Some(2020),
+                },
+                db.search_until("guide", far_future()).unwrap()[0].1
+            )]
+        );
+        let error = db.search_as::<Book>("rust").unwrap_err();
+        assert_eq!(format!("{}", error), "Failed to deserialize entry 4");
+        let values = db.search_as::<serde_json::Value>("rust").unwrap();
+        assert_eq!(values.len(), 3);
+    }
+
+    #[test]
+    fn max_results_caps_at_the_boundary() {
+        // apple in 1 to 5, with more apples for lower ids
+        let mut db = indexed(
+            (1..=6)
+                .map(|i| {
+                    let text = if i == 6 {
+                        "kiwi".to_string()
+                    } else {
+                        vec!["apple"; 6 - i].join(" ")
+                    };
+                    json!({"id": i, "text": text})
+                })
+                .collect(),
+        );
+        let uncapped = result_ids(&db.search_until("apple", far_future()).unwrap());
+        assert_eq!(uncapped, [1, 2, 3, 4, 5]);
+
+        for (max, expected) in [(5, &uncapped[..]), (6, &uncapped[..]), (4, &uncapped[..4])] {
+            db.set_max_results(max);
+            assert_eq!(
+                result_ids(&db.search_until("apple", far_future()).unwrap()),
+                expected,
+                "{}",
+                max
+            );
+            assert_eq!(db.search("apple").unwrap().len(), expected.len());
+        }
+        db.set_max_results(0);
+        assert!(db.search("apple").unwrap().is_empty());
+    }
+
+    #[test]
+    fn field_analyzer_changes_matching_for_that_field_only() {
+        let mut db = Database::new();
+        db.set_field_analyzer("meta.code", Box::new(NoopAnalyzer));
+        db.add(json!({"id": 1, "meta": {"code": "Ab-12", "name": "Ab-12"}}));
+        db.add(json!({"id": 2, "text": "ab 12"}));
+        db.add(json!({"id": 3, "text": "kiwi"}));
+        db.tokenize_entries().unwrap();
+
+        // the code is kept whole; its neighbour still goes through the lexer
+        assert_eq!(db.tokens_for(1).unwrap(), ["1", "Ab-12", "AB", "12"]);
+        assert_eq!(search_ids(&db, "meta.code:Ab-12"), [1]);
+        assert!(search_ids(&db, "meta.code:ab").is_empty());
+        assert!(search_ids(&db, "meta.code:AB-12").is_empty());
+        assert_eq!(search_ids(&db, "meta.name:ab"), [1]);
+        assert_eq!(search_ids(&db, "ab"), [1, 2]);
+        assert_eq!(search_ids(&db, "text:ab"), [2]);
+    }
+
+    #[test]
+    fn search_many_matches_individual_searches() {
+        let mut db = indexed(vec![
+            json!({"id": 1, "title": "apple pie", "text": "apple pear"}),
+            json!({"id": 2, "text": "apple"}),
+            json!({"id": 3, "text": "pear plum sofa"}),
+            json!({"id": 4, "text": "kiwi couch"}),
+        ]);
+        db.set_synonyms(HashMap::from([("sofa".into(), vec!["couch".into()])]), 0.5);
+        let queries = [
+            "apple",
+            "pear apple",
+            "title:apple",
+            "sofa",
+            "\"apple pear\"",
+            "",
+            "durian",
+        ];
+
+        let batch = db.search_many(&queries, 2);
+        assert_eq!(batch.len(), queries.len());
+        for (query, result) in queries.iter().zip(batch) {
+            let mut single = db.search_until(query, far_future()).unwrap();
+            single.truncate(2);
+            assert_eq!(result.unwrap(), single, "{}", query);
+        }
+
+        db.set_query_limits(8, 2);
+        let batch = db.search_many(&["apple", "apple pear plum", "apple"], 10);
+        assert!(batch[1].is_err());
+        assert_eq!(batch[0].as_ref().unwrap(), batch[2].as_ref().unwrap());
+    }
+
+    #[test]
+    fn folded_variants_match_exact_and_folded_queries() {
+        let mut db = Database::with_config(IndexConfig::default().folded_variants(true));
+        db.add(json!({"id": 1, "text": "Café crème"}));
+        db.add(json!({"id": 2, "text": "cafe"}));
+        db.add(json!({"id": 3, "text": "kiwi"}));
+        db.tokenize_entries().unwrap();
+
+        // variants follow the value's tokens, only where folding changes one;
+        // the lexer only uppercases ASCII
+        assert_eq!(
+            db.tokens_for(1).unwrap(),
+            ["1", "CAFé", "CRèME", "CAFE", "CREME"]
+        );
+        assert_eq!(db.tokens_for(2).unwrap(), ["2", "CAFE"]);
+        assert_eq!(search_ids(&db, "café"), [1]);
+        assert_eq!(search_ids(&db, "cafe"), [1, 2]);
+        assert_eq!(search_ids(&db, "creme"), [1]);
+        assert_eq!(search_ids(&db, "crème"), [1]);
+        assert_eq!(db.tokenize("café"), ["CAFé"]);
+    }
+
+    fn far_future() -> Instant {
+        Instant::now() + Duration::from_secs(60)
     }
 }
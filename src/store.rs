@@ -0,0 +1,59 @@
+use std::collections::HashMap;
+
+/// Backing storage for documents once they are indexed. Only the scoring data
+/// (`tf`, `tokens`, ...) has to live in memory; documents themselves are
+/// fetched from the store when results are resolved, so an implementation can
+/// keep them on disk or in an embedded database.
+pub trait DocumentStore: std::fmt::Debug + Send + Sync {
+    fn get(&self, id: u32) -> Option<serde_json::Value>;
+    /// Borrows a document instead of copying it, for stores that keep
+    /// documents in memory. Searches use this when they can and fall back to
+    /// `get`; the default always returns `None`.
+    fn get_ref(&self, _id: u32) -> Option<&serde_json::Value> {
+        None
+    }
+    fn put(&mut self, id: u32, entry: serde_json::Value);
+    fn remove(&mut self, id: u32) -> Option<serde_json::Value>;
+}
+
+/// A `DocumentStore` that keeps every document in a `HashMap`.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    entries: HashMap<u32, serde_json::Value>,
+}
+
+impl DocumentStore for MemoryStore {
+    fn get(&self, id: u32) -> Option<serde_json::Value> {
+        self.entries.get(&id).cloned()
+    }
+
+    fn get_ref(&self, id: u32) -> Option<&serde_json::Value> {
+        self.entries.get(&id)
+    }
+
+    fn put(&mut self, id: u32, entry: serde_json::Value) {
+        self.entries.insert(id, entry);
+    }
+
+    fn remove(&mut self, id: u32) -> Option<serde_json::Value> {
+        self.entries.remove(&id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn memory_store_round_trip() {
+        let mut store = MemoryStore::default();
+        store.put(1, json!({"id": 1}));
+
+        assert_eq!(store.get(1), Some(json!({"id": 1})));
+        assert_eq!(store.get_ref(1), Some(&json!({"id": 1})));
+        assert_eq!(store.remove(1), Some(json!({"id": 1})));
+        assert_eq!(store.get(1), None);
+    }
+}
@@ -1,57 +1,94 @@
+/// Splits text into number and word tokens, uppercasing words.
+///
+/// Whitespace and punctuation separate tokens and are never emitted on their
+/// own. Apostrophes and hyphens are kept when they join two letters, so
+/// "don't" stays `DON'T` and "state-of-the-art" stays `STATE-OF-THE-ART`, and
+/// a dot between two digits is kept so "4.7" stays `4.7`. Any other
+/// punctuation, including leading and trailing connectors, is stripped.
 #[derive(Debug)]
 pub struct Lexer<'a> {
     content: &'a [char],
+    position: usize,
+}
+
+/// A token along with where it was found, as `char` indices into the lexed
+/// content: `content[start..end]` is the token's source text before
+/// uppercasing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Span {
+    pub token: String,
+    pub start: usize,
+    pub end: usize,
 }
 
 impl<'a> Lexer<'a> {
     pub fn new(content: &'a [char]) -> Self {
-        Self { content }
+        Self {
+            content,
+            position: 0,
+        }
+    }
+
+    /// Turns the lexer into an iterator over tokens with their offsets.
+    pub fn spans(mut self) -> impl Iterator<Item = Span> + 'a {
+        std::iter::from_fn(move || self.next_span())
     }
 
     fn trim_left(&mut self) {
-        while self.content.len() > 0 && self.content[0].is_whitespace() {
-            self.content = &self.content[1..];
+        while !self.content.is_empty() && !self.content[0].is_alphanumeric() {
+            self.chop(1);
         }
     }
 
-    fn chop(&mut self, n: usize) -> &'a [char] {
-        let token = &self.content[0..n];
-        self.content = &self.content[n..];
-        token
+    fn is_joined_by(&self, n: usize, connectors: &[char], predicate: fn(&char) -> bool) -> bool {
+        self.content.len() > n + 1
+            && connectors.contains(&self.content[n])
+            && predicate(&self.content[n - 1])
+            && predicate(&self.content[n + 1])
     }
 
-    fn chop_while<P>(&mut self, mut predicate: P) -> &'a [char]
-    where
-        P: FnMut(&char) -> bool,
-    {
+    fn chop_joined(&mut self, connectors: &[char], predicate: fn(&char) -> bool) -> &'a [char] {
         let mut n = 0;
-        while self.content.len() > n && predicate(&self.content[n]) {
+        while self.content.len() > n
+            && (predicate(&self.content[n])
+                || (n > 0 && self.is_joined_by(n, connectors, predicate)))
+        {
             n += 1;
         }
 
         self.chop(n)
     }
 
-    fn next_token(&mut self) -> Option<String> {
+    fn chop(&mut self, n: usize) -> &'a [char] {
+        let token = &self.content[0..n];
+        self.content = &self.content[n..];
+        self.position += n;
+        token
+    }
+
+    fn next_span(&mut self) -> Option<Span> {
         self.trim_left();
-        if self.content.len() == 0 {
+        if self.content.is_empty() {
             return None;
         }
 
-        if self.content[0].is_numeric() {
-            return Some(self.chop_while(|c| c.is_numeric()).iter().collect());
-        }
-
-        if self.content[0].is_alphabetic() {
-            return Some(
-                self.chop_while(|c| c.is_alphabetic())
-                    .iter()
-                    .map(|c| c.to_ascii_uppercase())
-                    .collect(),
-            );
-        }
+        let start = self.position;
+        let token = if self.content[0].is_numeric() {
+            self.chop_joined(&['.'], |c| c.is_numeric())
+                .iter()
+                .collect()
+        } else {
+            self.chop_joined(&['\'', '-'], |c| c.is_alphabetic())
+                .iter()
+                .map(|c| c.to_ascii_uppercase())
+                .collect()
+        };
 
-        return Some(self.chop(1).iter().collect());
+        Some(Span {
+            token,
+            start,
+            end: self.position,
+        })
     }
 }
 
@@ -59,6 +96,59 @@ impl<'a> Iterator for Lexer<'a> {
     type Item = String;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.next_token()
+        self.next_span().map(|span| span.token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lex(text: &str) -> Vec<String> {
+        let chars = text.chars().collect::<Vec<_>>();
+        Lexer::new(&chars).collect()
+    }
+
+    #[test]
+    fn strips_punctuation_and_whitespace() {
+        assert_eq!(lex("  Hello,\tworld!\n"), ["HELLO", "WORLD"]);
+        assert_eq!(lex("(rust) & [serde]..."), ["RUST", "SERDE"]);
+        assert!(lex(" ?! -- ").is_empty());
+    }
+
+    #[test]
+    fn keeps_connectors_between_letters() {
+        assert_eq!(lex("don't"), ["DON'T"]);
+        assert_eq!(lex("state-of-the-art"), ["STATE-OF-THE-ART"]);
+        assert_eq!(lex("-trailing- 'quoted'"), ["TRAILING", "QUOTED"]);
+        assert_eq!(lex("a--b"), ["A", "B"]);
+    }
+
+    #[test]
+    fn keeps_decimal_points_between_digits() {
+        assert_eq!(lex("rated 4.7."), ["RATED", "4.7"]);
+        assert_eq!(lex("1.2.3"), ["1.2.3"]);
+        assert_eq!(lex("v2"), ["V", "2"]);
+    }
+
+    #[test]
+    fn spans_point_at_the_source_text() {
+        let chars = "Hi, there".chars().collect::<Vec<_>>();
+        let spans = Lexer::new(&chars).spans().collect::<Vec<_>>();
+        assert_eq!(
+            spans,
+            [
+                Span {
+                    token: "HI".to_string(),
+                    start: 0,
+                    end: 2
+                },
+                Span {
+                    token: "THERE".to_string(),
+                    start: 4,
+                    end: 9
+                },
+            ]
+        );
     }
 }